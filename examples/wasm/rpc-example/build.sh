@@ -6,7 +6,8 @@ set -e
 echo "Building RPC example WASM module..."
 
 # Build for wasm32-wasip1 target
-cargo build --target wasm32-wasip1 --release
+# Pass --no-default-features for hosts that provide the host.rpc_call import
+cargo build --target wasm32-wasip1 --release "$@"
 
 # Copy to examples/wasm directory (the library name is rpc_example.wasm)
 cp target/wasm32-wasip1/release/rpc_example.wasm ../rpc-example.wasm
@@ -3,21 +3,35 @@
  * 
  * This module demonstrates how to call host.rpc_call from guest WASM.
  * 
- * For now, uses a file-based communication protocol (since Wasmtime JS API is limited).
- * In a full Wasmtime implementation, this would use proper host imports.
+ * By default (the `file-rpc` feature) requests go through files in
+ * WASM_RPC_WORK_DIR, so the module runs under the plain wasmtime CLI. Hosts
+ * that provide the `host.rpc_call` import can build with
+ * `--no-default-features` to use it instead.
  */
 
 use std::alloc::{alloc as std_alloc, dealloc as std_dealloc, Layout};
 
 // Host import function signature
-// In a proper Wasmtime implementation, this would be:
-// #[link(wasm_import_module = "host")]
-// extern "C" {
-//     fn rpc_call(req_ptr: u32, req_len: u32, resp_ptr_ptr: u32) -> u32;
-// }
+// The host reads the request from (req_ptr, req_len), allocates the response
+// through our alloc() export, stores its pointer at resp_ptr_ptr and returns
+// the response length (0 on failure). It gives up after timeout_ms and returns
+// HOST_RPC_TIMED_OUT.
+#[cfg(all(target_arch = "wasm32", not(feature = "file-rpc")))]
+#[link(wasm_import_module = "host")]
+extern "C" {
+    fn rpc_call(req_ptr: u32, req_len: u32, resp_ptr_ptr: u32, timeout_ms: u32) -> u32;
+}
+
+/// host.rpc_call return value for a call that got no response within timeout_ms
+pub const HOST_RPC_TIMED_OUT: u32 = u32::MAX;
 
-// For file-based protocol, we'll use a helper function
-// In production with proper Wasmtime host imports, this would call the imported function directly
+/// Per-call wait for a response (WASM_RPC_TIMEOUT_MS, default 5000), used by both transports
+#[cfg(any(target_arch = "wasm32", feature = "file-rpc"))]
+fn rpc_timeout_ms() -> u64 {
+    std::env::var("WASM_RPC_TIMEOUT_MS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5000)
+}
 
 /// Allocate memory in WASM module
 /// This function is exported and called by the host to allocate response memory
@@ -38,20 +52,53 @@ pub extern "C" fn dealloc(ptr: *mut u8, len: u32) {
 
 /// Host RPC call helper
 /// 
-/// In a proper Wasmtime implementation, this would:
-/// 1. Call host.rpc_call with request pointer/length
+/// 1. Call host.rpc_call with request pointer/length and the call timeout
 /// 2. Host allocates memory via our alloc() export
-/// 3. Host writes response and returns pointer
-/// 4. We read response from memory and return it
+/// 3. Host writes response and stores the pointer at resp_ptr_ptr
+/// 4. We copy the response out of memory and release it with dealloc()
+#[cfg(not(feature = "file-rpc"))]
+pub fn host_rpc(req: &str) -> Result<String, String> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        let timeout_ms = u32::try_from(rpc_timeout_ms()).unwrap_or(HOST_RPC_TIMED_OUT - 1);
+        let mut resp_ptr: u32 = 0;
+        let resp_len = unsafe {
+            rpc_call(req.as_ptr() as u32, req.len() as u32, &mut resp_ptr as *mut u32 as u32, timeout_ms)
+        };
+
+        if resp_len == HOST_RPC_TIMED_OUT {
+            return Err("RPC call timeout".to_string());
+        }
+        if resp_len == 0 || resp_ptr == 0 {
+            return Err("host.rpc_call returned no response".to_string());
+        }
+
+        let bytes = unsafe {
+            std::slice::from_raw_parts(resp_ptr as *const u8, resp_len as usize).to_vec()
+        };
+        dealloc(resp_ptr as *mut u8, resp_len);
+
+        String::from_utf8(bytes).map_err(|e| format!("Response is not valid UTF-8: {}", e))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = req;
+        Err("host.rpc_call import is only available on wasm32 targets".to_string())
+    }
+}
+
+/// Host RPC call helper (file-based protocol)
 /// 
-/// For now, uses file-based protocol as a workaround.
+/// Uses WASI pre-opened directories (via --dir flag in wasmtime).
+/// The work directory is pre-opened and accessible via its path.
+#[cfg(feature = "file-rpc")]
 pub fn host_rpc(req: &str) -> Result<String, String> {
-    // File-based protocol implementation
-    // Uses WASI pre-opened directories (via --dir flag in wasmtime)
-    // The work directory is pre-opened and accessible via its path
     use std::env;
     use std::fs;
     use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
     
     // Get the work directory path from environment
     let work_dir = env::var("WASM_RPC_WORK_DIR")
@@ -69,9 +116,12 @@ pub fn host_rpc(req: &str) -> Result<String, String> {
     fs::write(&request_path, req)
         .map_err(|e| format!("Failed to write request: {} (path: {:?})", e, request_path))?;
     
-    // Poll for response (with timeout)
-    let max_wait_ms = 5000;
-    let poll_interval_ms = 10;
+    // Poll for response (with timeout, configurable like the rebalance module's RpcPolicy)
+    let max_wait_ms = rpc_timeout_ms();
+    let poll_interval_ms: u64 = env::var("WASM_RPC_POLL_MS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10)
+        .max(1);
     let max_iterations = max_wait_ms / poll_interval_ms;
     
     for _ in 0..max_iterations {
@@ -87,13 +137,11 @@ pub fn host_rpc(req: &str) -> Result<String, String> {
             return Ok(response);
         }
         
-        // Sleep (in WASM, we can't sleep, so we just spin)
-        // In a real implementation, we'd use proper async/await or host sleep
-        for _ in 0..1000 {
-            // Spin wait
-        }
+        // WASI implements sleep via poll_oneoff, so this actually yields to the host
+        thread::sleep(Duration::from_millis(poll_interval_ms));
     }
     
+    let _ = fs::remove_file(&request_path);
     Err("RPC call timeout".to_string())
 }
 
@@ -73,6 +73,46 @@ describe('WasmHostBridge', () => {
     expect(response.result).toBeUndefined();
   });
 
+  it('should answer a batch request with an array of responses', async () => {
+    const batch: JsonRpcRequest[] = [
+      { jsonrpc: '2.0', id: 10, method: 'eth_blockNumber', params: [] },
+      { jsonrpc: '2.0', id: 11, method: 'eth_sendRawTransaction', params: ['0x...'] },
+    ];
+
+    const requestPath = join(workDir, RPC_REQUEST_FILE);
+    await fs.writeFile(requestPath, JSON.stringify(batch), 'utf-8');
+
+    await processWasmRpcRequests(workDir);
+
+    const responsePath = join(workDir, RPC_RESPONSE_FILE);
+    const responseData = await fs.readFile(responsePath, 'utf-8');
+    const responses = JSON.parse(responseData);
+
+    expect(Array.isArray(responses)).toBe(true);
+    expect(responses).toHaveLength(2);
+    expect(responses[0].id).toBe(10);
+    expect(responses[0].result).toBeDefined();
+    expect(responses[1].id).toBe(11);
+    expect(responses[1].error?.code).toBe(-32601); // Method not found
+  });
+
+  it('should answer concurrent keyed request files separately', async () => {
+    const keys = ['run1-1', 'run1-2'];
+    await Promise.all(keys.map((key, i) => fs.writeFile(
+      join(workDir, `wasm_rpc_request.${key}.json`),
+      JSON.stringify({ jsonrpc: '2.0', id: key, method: 'eth_blockNumber', params: [] }),
+      'utf-8',
+    )));
+
+    const found = await processWasmRpcRequests(workDir);
+
+    expect(found).toBe(2);
+    for (const key of keys) {
+      const responseData = await fs.readFile(join(workDir, `wasm_rpc_response.${key}.json`), 'utf-8');
+      expect(JSON.parse(responseData).id).toBe(key);
+    }
+  });
+
   it('should reject invalid JSON', async () => {
     const requestPath = join(workDir, RPC_REQUEST_FILE);
     await fs.writeFile(requestPath, 'invalid json{', 'utf-8');
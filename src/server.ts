@@ -141,7 +141,6 @@ async function runWasmtimeOnce(params: {
   let rpcProcessor: NodeJS.Timeout | null = null;
   let rpcTicks = 0;
   let rpcFilesFound = 0;
-  const requestPath = `${workDir}/wasm_rpc_request.json`;
   
   try {
     const { processWasmRpcRequests } = await import('./utils/wasmHostBridge.js');
@@ -149,16 +148,14 @@ async function runWasmtimeOnce(params: {
     rpcProcessor = setInterval(async () => {
       rpcTicks++;
       try {
-        // Check if WASM wrote a request file
-        await fs.access(requestPath);
-        rpcFilesFound++;
-        logger.info({ requestPath, rpcTicks }, 'RPC request file found');
-        await processWasmRpcRequests(workDir);
-      } catch (err: any) {
-        // ENOENT is expected - no request file yet
-        if (err.code !== 'ENOENT') {
-          logger.error({ error: err, workDir }, 'RPC processor error');
+        // Process every request file the WASM has written (one per in-flight call)
+        const found = await processWasmRpcRequests(workDir);
+        if (found > 0) {
+          rpcFilesFound += found;
+          logger.info({ workDir, found, rpcTicks }, 'RPC request files processed');
         }
+      } catch (err: any) {
+        logger.error({ error: err, workDir }, 'RPC processor error');
       }
     }, 10); // Poll every 10ms
   } catch (error) {
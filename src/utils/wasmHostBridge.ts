@@ -22,6 +22,10 @@ const logger = getLogger('WasmHostBridge');
 
 const RPC_REQUEST_FILE = 'wasm_rpc_request.json';
 const RPC_RESPONSE_FILE = 'wasm_rpc_response.json';
+const RPC_REQUEST_STEM = RPC_REQUEST_FILE.replace(/\.json$/, '');
+const RPC_RESPONSE_STEM = RPC_RESPONSE_FILE.replace(/\.json$/, '');
+// Guests write one file per request: wasm_rpc_request.<requestKey>.json
+const KEYED_REQUEST_FILE = new RegExp(`^${RPC_REQUEST_STEM}\\.[A-Za-z0-9_-]+\\.json$`);
 const MAX_REQUEST_SIZE = 64 * 1024; // 64KB
 const MAX_RESPONSE_SIZE = 1024 * 1024; // 1MB
 const RPC_TIMEOUT_MS = 5000; // 5 seconds - network RPC calls need time
@@ -91,16 +95,110 @@ async function executeViaProxy(request: JsonRpcRequest): Promise<JsonRpcResponse
   });
 }
 
+/**
+ * Execute a single JSON-RPC request via proxy or the local simulator
+ */
+async function executeRequest(request: JsonRpcRequest): Promise<JsonRpcResponse> {
+  logger.info({ method: request.method, params: JSON.stringify(request.params || []).substring(0, 200) }, 'Executing RPC method');
+
+  if (RPC_PROXY_URL) {
+    // Proxy mode: call simulator's /rpc/proxy endpoint
+    logger.info({ proxyUrl: RPC_PROXY_URL }, 'Using RPC proxy mode');
+    try {
+      const response = await executeViaProxy(request);
+      logger.info({ method: request.method, hasError: !!response.error }, 'RPC proxy call completed');
+      return response;
+    } catch (error: any) {
+      const errMsg = error?.message || error?.code || String(error);
+      logger.error({ error: errMsg, method: request.method }, 'RPC proxy call failed');
+      return {
+        jsonrpc: '2.0' as const,
+        id: request.id ?? null,
+        error: {
+          code: -32000,
+          message: 'Proxy error',
+          data: errMsg,
+        },
+      };
+    }
+  }
+
+  // Direct mode: use local RpcSimulator
+  const { getRpcSimulator } = await import('./rpcSimulator.js');
+  const simulator = getRpcSimulator();
+
+  // Execute with timeout
+  let timedOut = false;
+  const timeoutPromise = new Promise<JsonRpcResponse>((resolve) => {
+    setTimeout(() => {
+      timedOut = true;
+      logger.warn({ method: request.method, timeoutMs: RPC_TIMEOUT_MS }, 'RPC call timeout');
+      resolve({
+        jsonrpc: '2.0' as const,
+        id: request.id ?? null,
+        error: {
+          code: -32000,
+          message: 'Server error',
+          data: `RPC call timeout after ${RPC_TIMEOUT_MS}ms`,
+        },
+      });
+    }, RPC_TIMEOUT_MS);
+  });
+
+  const executePromise = simulator.execute(request);
+  const response = await Promise.race([executePromise, timeoutPromise]);
+
+  if (!timedOut) {
+    logger.info({ method: request.method, hasError: !!response.error }, 'Direct RPC call completed');
+  }
+  return response;
+}
+
+/**
+ * Whether a work dir entry is a pending guest request (legacy fixed name or keyed)
+ */
+function isRequestFile(name: string): boolean {
+  return name === RPC_REQUEST_FILE || KEYED_REQUEST_FILE.test(name);
+}
+
+/**
+ * Write a response atomically so the polling guest never reads a partial file
+ */
+async function writeResponse(responsePath: string, data: string): Promise<void> {
+  const tmpPath = responsePath + '.tmp';
+  await fs.writeFile(tmpPath, data, 'utf-8');
+  await fs.rename(tmpPath, responsePath);
+}
+
 /**
  * Process RPC requests from guest WASM module
  * 
- * This function reads RPC requests from a file (written by guest),
- * processes them via the simulator (direct or proxy), and writes responses back.
+ * Scans the work directory for request files (written by guest), processes
+ * them concurrently via the simulator (direct or proxy), and writes each
+ * response next to its request under the matching response name.
+ * Returns the number of request files found.
  */
-export async function processWasmRpcRequests(workDir: string): Promise<void> {
-  const requestPath = join(workDir, RPC_REQUEST_FILE);
-  const responsePath = join(workDir, RPC_RESPONSE_FILE);
-  const processingPath = join(workDir, RPC_REQUEST_FILE + '.processing');
+export async function processWasmRpcRequests(workDir: string): Promise<number> {
+  let entries: string[];
+  try {
+    entries = await fs.readdir(workDir);
+  } catch (err: any) {
+    if (err.code === 'ENOENT') return 0;
+    throw err;
+  }
+
+  const requestFiles = entries.filter(isRequestFile);
+  await Promise.all(requestFiles.map((file) => processRequestFile(workDir, file)));
+  return requestFiles.length;
+}
+
+/**
+ * Process a single request file
+ */
+async function processRequestFile(workDir: string, requestFile: string): Promise<void> {
+  const requestPath = join(workDir, requestFile);
+  const responsePath = join(workDir, RPC_RESPONSE_STEM + requestFile.slice(RPC_REQUEST_STEM.length));
+  const processingPath = requestPath + '.processing';
   
   try {
     // Atomically claim the request by renaming it
@@ -137,12 +235,12 @@ export async function processWasmRpcRequests(workDir: string): Promise<void> {
           data: `Request too large (max ${MAX_REQUEST_SIZE} bytes)`,
         },
       };
-      await fs.writeFile(responsePath, JSON.stringify(errorResponse), 'utf-8');
+      await writeResponse(responsePath, JSON.stringify(errorResponse));
       return;
     }
 
-    // Parse JSON-RPC request
-    let request: JsonRpcRequest;
+    // Parse JSON-RPC request (single object or batch array)
+    let request: JsonRpcRequest | JsonRpcRequest[];
     try {
       request = JSON.parse(requestData);
     } catch (error) {
@@ -154,63 +252,15 @@ export async function processWasmRpcRequests(workDir: string): Promise<void> {
           message: 'Parse error',
         },
       };
-      await fs.writeFile(responsePath, JSON.stringify(errorResponse), 'utf-8');
+      await writeResponse(responsePath, JSON.stringify(errorResponse));
       return;
     }
 
-    logger.info({ method: request.method, params: JSON.stringify(request.params || []).substring(0, 200) }, 'Executing RPC method');
-    
-    let response: JsonRpcResponse;
-    
-    if (RPC_PROXY_URL) {
-      // Proxy mode: call simulator's /rpc/proxy endpoint
-      logger.info({ proxyUrl: RPC_PROXY_URL }, 'Using RPC proxy mode');
-      try {
-        response = await executeViaProxy(request);
-        logger.info({ method: request.method, hasError: !!response.error }, 'RPC proxy call completed');
-      } catch (error: any) {
-        const errMsg = error?.message || error?.code || String(error);
-        logger.error({ error: errMsg, method: request.method }, 'RPC proxy call failed');
-        response = {
-          jsonrpc: '2.0' as const,
-          id: request.id ?? null,
-          error: {
-            code: -32000,
-            message: 'Proxy error',
-            data: errMsg,
-          },
-        };
-      }
-    } else {
-      // Direct mode: use local RpcSimulator
-      const { getRpcSimulator } = await import('./rpcSimulator.js');
-      const simulator = getRpcSimulator();
-      
-      // Execute with timeout
-      let timedOut = false;
-      const timeoutPromise = new Promise<JsonRpcResponse>((resolve) => {
-        setTimeout(() => {
-          timedOut = true;
-          logger.warn({ method: request.method, timeoutMs: RPC_TIMEOUT_MS }, 'RPC call timeout');
-          resolve({
-            jsonrpc: '2.0' as const,
-            id: request.id ?? null,
-            error: {
-              code: -32000,
-              message: 'Server error',
-              data: `RPC call timeout after ${RPC_TIMEOUT_MS}ms`,
-            },
-          });
-        }, RPC_TIMEOUT_MS);
-      });
+    // JSON-RPC batch: execute every item and answer with an array in one response file
+    const response: JsonRpcResponse | JsonRpcResponse[] = Array.isArray(request)
+      ? await Promise.all(request.map((item) => executeRequest(item)))
+      : await executeRequest(request);
 
-      const executePromise = simulator.execute(request);
-      response = await Promise.race([executePromise, timeoutPromise]);
-      
-      if (!timedOut) {
-        logger.info({ method: request.method, hasError: !!response.error }, 'Direct RPC call completed');
-      }
-    }
     logger.debug({ response: JSON.stringify(response).substring(0, 200) }, 'RPC response');
 
     // Validate response size
@@ -218,19 +268,19 @@ export async function processWasmRpcRequests(workDir: string): Promise<void> {
     if (responseStr.length > MAX_RESPONSE_SIZE) {
       const errorResponse: JsonRpcResponse = {
         jsonrpc: '2.0' as const,
-        id: request.id ?? null,
+        id: Array.isArray(request) ? null : request.id ?? null,
         error: {
           code: -32000,
           message: 'Server error',
           data: `Response too large (max ${MAX_RESPONSE_SIZE} bytes)`,
         },
       };
-      await fs.writeFile(responsePath, JSON.stringify(errorResponse), 'utf-8');
+      await writeResponse(responsePath, JSON.stringify(errorResponse));
       return;
     }
 
     // Write response
-    await fs.writeFile(responsePath, responseStr, 'utf-8');
+    await writeResponse(responsePath, responseStr);
     logger.info({ responsePath }, 'Response written');
   } catch (error: any) {
     logger.error({ error: error.message, code: error.code, path: error.path }, 'RPC processing error');
@@ -252,7 +302,7 @@ export async function processWasmRpcRequests(workDir: string): Promise<void> {
       },
     };
     try {
-      await fs.writeFile(responsePath, JSON.stringify(errorResponse), 'utf-8');
+      await writeResponse(responsePath, JSON.stringify(errorResponse));
     } catch (writeErr: any) {
       logger.error({ error: writeErr.message }, 'Failed to write error response');
     }
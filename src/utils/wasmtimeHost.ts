@@ -57,8 +57,9 @@ export function setupHostImports(linker: Linker, store: Store): void {
   const simulator = getRpcSimulator();
 
   // Define host.rpc_call function
-  // Signature: (req_ptr: u32, req_len: u32, resp_ptr_ptr: u32) -> u32
-  // Returns: response length, or 0 on error (error details written to response)
+  // Signature: (req_ptr: u32, req_len: u32, resp_ptr_ptr: u32, timeout_ms: u32) -> u32
+  // Returns: response length, 0 on error (error details written to response),
+  // or 0xFFFFFFFF if no response arrived within timeout_ms
   
   // Note: The actual implementation depends on the Wasmtime JS API
   // The wasmtime package v0.0.2 may not have full Linker API
@@ -105,10 +105,10 @@ function setupHostImports(linker: Linker, store: Store): void {
   // The function should:
   // 1. Read request from guest memory (req_ptr, req_len)
   // 2. Parse JSON-RPC request
-  // 3. Validate and execute via simulator
+  // 3. Validate and execute via simulator, giving up after timeout_ms (4th argument)
   // 4. Call guest alloc() to allocate response memory
   // 5. Write response to guest memory
   // 6. Store pointer at resp_ptr_ptr
-  // 7. Return response length
+  // 7. Return response length, or 0xFFFFFFFF on timeout
 }
 
@@ -13,8 +13,10 @@ if ! rustup target list | grep -q "$TARGET (installed)"; then
 fi
 
 # Build for WASM
+# Pass --no-default-features for hosts that provide the host.rpc_call import;
+# the default build uses the WASM_RPC_WORK_DIR file exchange and runs under `wasmtime run`
 echo "Building WASM module..."
-cargo build --target $TARGET --release
+cargo build --target $TARGET --release "$@"
 
 # Copy WASM to project root
 cp target/$TARGET/release/rebalance_wasm.wasm ./yield-optimizer.wasm
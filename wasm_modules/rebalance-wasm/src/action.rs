@@ -0,0 +1,140 @@
+//! Action Registry
+//!
+//! Every action the module can run implements [`Action`] and is listed once in
+//! [`ACTIONS`]. The dispatcher in `lib.rs`, the `describe` output and the
+//! unknown-action error are all derived from that list, so adding an action is
+//! one `impl Action` plus one registry entry.
+
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Instant;
+
+use crate::common::{self, Envelope, Telemetry};
+use crate::describe::Describe;
+use crate::error::ModuleError;
+use crate::rebalance::Rebalance;
+
+/// Action run when the input has no `action` field
+pub const DEFAULT_ACTION: &str = "rebalance";
+
+/// A typed module action
+pub trait Action {
+    /// Value of the input's `action` field that selects this action
+    const NAME: &'static str;
+    /// One-line summary reported by `describe`
+    const DESCRIPTION: &'static str;
+
+    type Input: DeserializeOwned + JsonSchema;
+    /// Result payload of the success envelope
+    type Output: Serialize + JsonSchema;
+
+    fn run(&self, input: Self::Input) -> Result<Self::Output, ModuleError>;
+}
+
+/// Name, summary and JSON Schemas of one action, as reported by `describe`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ActionDescription {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// JSON Schema of the input
+    pub input: Value,
+    /// JSON Schema of the envelope printed on success
+    pub output: Value,
+}
+
+/// Object-safe view of an [`Action`], so actions with different input and
+/// output types can share one registry
+pub trait ActionHandler: Sync {
+    fn name(&self) -> &'static str;
+
+    fn describe(&self) -> ActionDescription;
+
+    /// Parse `input`, run the action and return its output line
+    fn execute(&self, input: &Value) -> String;
+}
+
+impl<A: Action + Sync> ActionHandler for A {
+    fn name(&self) -> &'static str {
+        A::NAME
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription {
+            name: A::NAME,
+            description: A::DESCRIPTION,
+            input: schema::<A::Input>(),
+            output: schema::<Envelope<A::Output>>(),
+        }
+    }
+
+    fn execute(&self, input: &Value) -> String {
+        let started = Instant::now();
+        let outcome = serde_json::from_value::<A::Input>(input.clone())
+            .map_err(|e| ModuleError::InvalidInput(format!("Invalid input JSON: {}", e)))
+            .and_then(|parsed| self.run(parsed));
+        common::output(A::NAME, input, outcome, Some(Telemetry::since(started)))
+    }
+}
+
+/// All actions, in the order `describe` lists them
+pub const ACTIONS: &[&dyn ActionHandler] = &[&Rebalance, &Describe];
+
+/// JSON Schema (draft-07) of `T`
+pub fn schema<T: JsonSchema>() -> Value {
+    serde_json::to_value(schema_for!(T)).unwrap_or(Value::Null)
+}
+
+pub fn find(name: &str) -> Option<&'static dyn ActionHandler> {
+    ACTIONS.iter().copied().find(|action| action.name() == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    ACTIONS.iter().map(|action| action.name()).collect()
+}
+
+fn unknown_action(name: &str) -> ModuleError {
+    ModuleError::InvalidInput(format!("Unknown action: {}. Valid actions: {}", name, names().join(", ")))
+}
+
+/// Run the action selected by the input's `action` field and return its output line
+pub fn dispatch(input: &Value) -> String {
+    let name = input.get("action")
+        .and_then(|v| v.as_str())
+        .unwrap_or(DEFAULT_ACTION);
+
+    log_info!("Dispatching action"; action = name);
+
+    match find(name) {
+        Some(action) => action.execute(input),
+        None => {
+            log_error!("Unknown action: {}", name);
+            common::output::<()>(name, input, Err(unknown_action(name)), None)
+        }
+    }
+}
+
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::describe::describe;
+
+    #[test]
+    fn test_registry_drives_describe_and_unknown_action_error() {
+        assert_eq!(names(), vec!["rebalance", "describe"]);
+        assert!(find(DEFAULT_ACTION).is_some());
+        assert!(find("emergency-check").is_none());
+
+        let described: Vec<&str> = describe().actions.iter().map(|a| a.name).collect();
+        assert_eq!(described, names());
+
+        let error = unknown_action("emergency-check");
+        assert_eq!(error.code(), "INVALID_INPUT");
+        assert_eq!(error.to_string(), "Unknown action: emergency-check. Valid actions: rebalance, describe");
+    }
+}
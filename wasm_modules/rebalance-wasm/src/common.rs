@@ -3,132 +3,945 @@
 //! Contains RPC communication, logging, and common data structures.
 
 use std::alloc::{alloc as std_alloc, dealloc as std_dealloc, Layout};
+use ethereum_types::{Address, H256, U256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
 use serde_json::{json, Value};
+use crate::error::{ModuleError, TRANSIENT_RPC_CODES};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::fs;
 use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // ============================================================================
 // WASM Memory Exports (required by host)
 // ============================================================================
 
+/// Allocate `len` bytes (any size, byte-aligned) for the host to write into
+///
+/// `alloc(0)` returns a dangling non-null pointer that must not be read;
+/// `dealloc` ignores it. Returns null if the allocation fails.
 #[no_mangle]
 pub extern "C" fn alloc(len: u32) -> *mut u8 {
-    let layout = Layout::from_size_align(len as usize, 1).unwrap();
-    unsafe { std_alloc(layout) }
+    if len == 0 {
+        return NonNull::<u8>::dangling().as_ptr();
+    }
+    match Layout::from_size_align(len as usize, 1) {
+        Ok(layout) => unsafe { std_alloc(layout) },
+        Err(_) => std::ptr::null_mut(),
+    }
 }
 
+/// Release a buffer returned by `alloc(len)`
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn dealloc(ptr: *mut u8, len: u32) {
-    if !ptr.is_null() {
-        let layout = Layout::from_size_align(len as usize, 1).unwrap();
+    if ptr.is_null() || len == 0 {
+        return;
+    }
+    if let Ok(layout) = Layout::from_size_align(len as usize, 1) {
         unsafe { std_dealloc(ptr, layout) };
     }
 }
 
 // ============================================================================
-// Logging Macros
+// Logging
 // ============================================================================
+//
+// Log records are written to stderr as NDJSON, one object per line:
+//   {"seq":3,"level":"info","target":"rebalance_wasm::common","msg":"...","fields":{...}}
+// `seq` increases monotonically within a run so the host can order and index
+// records per job. Verbosity comes from WASM_LOG or the input `log` field,
+// using env_logger-style directives: `debug` or `info,rebalance_wasm::common=debug`.
 
+/// Write a log record at the given level.
+///
+/// Key/value fields follow the message after a `;`:
+/// `log_info!("Fetched {} items", n; block = 123, vault = addr)`
 #[macro_export]
-macro_rules! log_info {
-    ($($arg:tt)*) => {
-        eprintln!("[WASM INFO] {}", format!($($arg)*));
+macro_rules! log_at {
+    ($level:expr, $fmt:literal $(, $arg:expr)* ; $($key:ident = $val:expr),+ $(,)?) => {
+        if $crate::common::log_enabled($level, module_path!()) {
+            $crate::common::write_log($level, module_path!(), &format!($fmt $(, $arg)*),
+                vec![$((stringify!($key), $crate::common::log_field(&$val))),+]);
+        }
+    };
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::common::log_enabled($level, module_path!()) {
+            $crate::common::write_log($level, module_path!(), &format!($($arg)*), Vec::new());
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_error {
     ($($arg:tt)*) => {
-        eprintln!("[WASM ERROR] {}", format!($($arg)*));
+        $crate::log_at!($crate::common::LogLevel::Error, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::log_at!($crate::common::LogLevel::Warn, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::log_at!($crate::common::LogLevel::Info, $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_debug {
     ($($arg:tt)*) => {
-        eprintln!("[WASM DEBUG] {}", format!($($arg)*));
+        $crate::log_at!($crate::common::LogLevel::Debug, $($arg)*)
     };
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// Parsed WASM_LOG directives: a default level plus per-target overrides.
+/// `None` levels mean "off".
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogDirectives {
+    default: Option<LogLevel>,
+    targets: Vec<(String, Option<LogLevel>)>,
+}
+
+impl Default for LogDirectives {
+    fn default() -> Self {
+        Self { default: Some(LogLevel::Info), targets: Vec::new() }
+    }
+}
+
+impl LogDirectives {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filter.targets.push((target.trim().to_string(), parse_log_level(level)?)),
+                None => filter.default = parse_log_level(directive)?,
+            }
+        }
+        // Longest target prefix wins
+        filter.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(filter)
+    }
+
+    pub fn enabled(&self, level: LogLevel, target: &str) -> bool {
+        let max = self.targets.iter()
+            .find(|(prefix, _)| target == prefix || target.starts_with(&format!("{}::", prefix)))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default);
+        max.is_some_and(|max| level <= max)
+    }
+}
+
+fn parse_log_level(level: &str) -> Result<Option<LogLevel>, String> {
+    match level.trim().to_ascii_lowercase().as_str() {
+        "off" => Ok(None),
+        "error" => Ok(Some(LogLevel::Error)),
+        "warn" => Ok(Some(LogLevel::Warn)),
+        "info" => Ok(Some(LogLevel::Info)),
+        "debug" => Ok(Some(LogLevel::Debug)),
+        "trace" => Ok(Some(LogLevel::Trace)),
+        other => Err(format!("Unknown log level: {}", other)),
+    }
+}
+
+static LOG_FILTER: Mutex<Option<LogDirectives>> = Mutex::new(None);
+static LOG_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn with_log_filter<T>(f: impl FnOnce(&LogDirectives) -> T) -> T {
+    let mut guard = LOG_FILTER.lock().unwrap_or_else(|e| e.into_inner());
+    let filter = guard.get_or_insert_with(|| {
+        env::var("WASM_LOG").ok()
+            .and_then(|spec| LogDirectives::parse(&spec).ok())
+            .unwrap_or_default()
+    });
+    f(filter)
+}
+
+/// Apply the input `log` field, which takes precedence over WASM_LOG
+pub fn init_logging(input: &Value) {
+    let Some(spec) = input.get("log").and_then(|v| v.as_str()) else { return };
+    match LogDirectives::parse(spec) {
+        Ok(filter) => *LOG_FILTER.lock().unwrap_or_else(|e| e.into_inner()) = Some(filter),
+        Err(e) => log_warn!("Ignoring input log filter: {}", e; spec = spec),
+    }
+}
+
+pub fn log_enabled(level: LogLevel, target: &str) -> bool {
+    with_log_filter(|filter| filter.enabled(level, target))
+}
+
+pub fn log_field<T: serde::Serialize + ?Sized>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or_else(|e| Value::String(format!("<unserializable: {}>", e)))
+}
+
+pub fn log_record(level: LogLevel, target: &str, msg: &str, fields: Vec<(&str, Value)>) -> Value {
+    let mut record = json!({
+        "seq": LOG_SEQ.fetch_add(1, Ordering::Relaxed),
+        "level": level.as_str(),
+        "target": target,
+        "msg": msg,
+    });
+    if !fields.is_empty() {
+        record["fields"] = Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    }
+    record
+}
+
+pub fn write_log(level: LogLevel, target: &str, msg: &str, fields: Vec<(&str, Value)>) {
+    eprintln!("{}", log_record(level, target, msg, fields));
+}
+
 // ============================================================================
 // RPC Communication Layer
 // ============================================================================
+//
+// Two transports are available:
+// - `file-rpc` feature (default): file exchange in WASM_RPC_WORK_DIR, for hosts
+//   that run modules through the wasmtime CLI.
+// - without it: the `host.rpc_call` import. The host reads the request from
+//   guest memory, allocates the response via our `alloc` export and writes the
+//   response pointer to `resp_ptr_ptr`. It gives up after `timeout_ms` and
+//   returns `HOST_RPC_TIMED_OUT`.
+
+#[cfg(all(target_arch = "wasm32", not(feature = "file-rpc")))]
+#[link(wasm_import_module = "host")]
+extern "C" {
+    #[link_name = "rpc_call"]
+    fn host_rpc_call(req_ptr: u32, req_len: u32, resp_ptr_ptr: u32, timeout_ms: u32) -> u32;
+}
+
+/// `host.rpc_call` return value for a call that got no response within `timeout_ms`
+pub const HOST_RPC_TIMED_OUT: u32 = u32::MAX;
 
 /// RPC configuration from environment
 pub struct RpcConfig {
     pub work_dir: String,
     pub request_file: String,
     pub response_file: String,
+    /// Unique per module run; prefixes every request key so runs sharing a work dir never collide
+    pub run_id: String,
+    pub policy: RpcPolicy,
+    /// Record or replay RPC traffic (see [`Cassette`])
+    pub cassette: Option<Cassette>,
+    started: Instant,
+    calls_made: Cell<u32>,
+}
+
+/// Sequence number of the next request of this run
+static NEXT_REQUEST_SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// Timeout, retry and budget policy for RPC calls of one run
+///
+/// Read from `WASM_RPC_*` env vars, then overridden by the input's `rpc` object:
+/// `{ "timeoutMs", "pollIntervalMs", "deadlineMs", "maxRetries", "backoffMs", "maxCalls" }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcPolicy {
+    /// Per-attempt wait for a response; passed to `host.rpc_call`, or polled for
+    /// by the file transport
+    pub call_timeout: Duration,
+    /// File transport polling interval
+    pub poll_interval: Duration,
+    /// Overall budget for all RPC activity of the run, measured from startup
+    pub run_deadline: Option<Duration>,
+    /// Extra attempts after a timeout or transient JSON-RPC error
+    pub max_retries: u32,
+    /// First retry delay; doubled for every further attempt
+    pub backoff: Duration,
+    /// Maximum number of attempts (retries included) per run
+    pub max_calls: Option<u32>,
+}
+
+impl Default for RpcPolicy {
+    fn default() -> Self {
+        Self {
+            call_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(10),
+            run_deadline: None,
+            max_retries: 0,
+            backoff: Duration::from_millis(100),
+            max_calls: None,
+        }
+    }
+}
+
+/// The input's `rpc` object; unset fields keep the env/default value
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcPolicyOverrides {
+    /// Per-attempt wait for a response
+    pub timeout_ms: Option<u64>,
+    /// File transport polling interval
+    pub poll_interval_ms: Option<u64>,
+    /// Overall budget for all RPC activity of the run
+    pub deadline_ms: Option<u64>,
+    /// Extra attempts after a timeout or transient JSON-RPC error
+    pub max_retries: Option<u32>,
+    /// First retry delay; doubled for every further attempt
+    pub backoff_ms: Option<u64>,
+    /// Maximum number of attempts (retries included) per run
+    pub max_calls: Option<u32>,
+}
+
+impl RpcPolicy {
+    /// Load the policy from `WASM_RPC_TIMEOUT_MS`, `WASM_RPC_POLL_MS`, `WASM_RPC_DEADLINE_MS`,
+    /// `WASM_RPC_MAX_RETRIES`, `WASM_RPC_BACKOFF_MS` and `WASM_RPC_MAX_CALLS`
+    pub fn from_env() -> Result<Self, ModuleError> {
+        fn var<T: std::str::FromStr>(name: &str) -> Result<Option<T>, ModuleError> {
+            match env::var(name) {
+                Ok(v) => v.trim().parse().map(Some)
+                    .map_err(|_| ModuleError::InvalidInput(format!("Invalid {}: {}", name, v))),
+                Err(_) => Ok(None),
+            }
+        }
+
+        let mut policy = Self::default();
+        policy.apply(&RpcPolicyOverrides {
+            timeout_ms: var("WASM_RPC_TIMEOUT_MS")?,
+            poll_interval_ms: var("WASM_RPC_POLL_MS")?,
+            deadline_ms: var("WASM_RPC_DEADLINE_MS")?,
+            max_retries: var("WASM_RPC_MAX_RETRIES")?,
+            backoff_ms: var("WASM_RPC_BACKOFF_MS")?,
+            max_calls: var("WASM_RPC_MAX_CALLS")?,
+        });
+        Ok(policy)
+    }
+
+    /// Apply already-parsed `rpc` overrides on top of this policy
+    pub fn with_overrides(mut self, overrides: &RpcPolicyOverrides) -> Self {
+        self.apply(overrides);
+        self
+    }
+
+    fn apply(&mut self, o: &RpcPolicyOverrides) {
+        if let Some(ms) = o.timeout_ms { self.call_timeout = Duration::from_millis(ms); }
+        if let Some(ms) = o.poll_interval_ms { self.poll_interval = Duration::from_millis(ms.max(1)); }
+        if let Some(ms) = o.deadline_ms { self.run_deadline = Some(Duration::from_millis(ms)); }
+        if let Some(n) = o.max_retries { self.max_retries = n; }
+        if let Some(ms) = o.backoff_ms { self.backoff = Duration::from_millis(ms); }
+        if let Some(n) = o.max_calls { self.max_calls = Some(n); }
+    }
+
+    /// Delay before retry number `attempt` (0-based)
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1u32 << attempt.min(16))
+    }
 }
 
 impl RpcConfig {
     /// Load RPC config from environment variables
-    pub fn from_env() -> Result<Self, String> {
-        let work_dir = env::var("WASM_RPC_WORK_DIR")
-            .map_err(|_| "WASM_RPC_WORK_DIR not set")?;
+    ///
+    /// WASM_RPC_WORK_DIR is only required by the `file-rpc` transport, and not
+    /// at all when replaying a cassette.
+    pub fn from_env() -> Result<Self, ModuleError> {
+        let cassette_mode = CassetteMode::from_env()?;
+        let work_dir = match env::var("WASM_RPC_WORK_DIR") {
+            Ok(dir) => dir,
+            Err(_) if cfg!(feature = "file-rpc") && cassette_mode != Some(CassetteMode::Replay) => {
+                return Err(ModuleError::InvalidInput("WASM_RPC_WORK_DIR not set".to_string()));
+            }
+            Err(_) => ".".to_string(),
+        };
         let request_file = env::var("WASM_RPC_REQUEST_FILE")
             .unwrap_or_else(|_| "wasm_rpc_request.json".to_string());
         let response_file = env::var("WASM_RPC_RESPONSE_FILE")
             .unwrap_or_else(|_| "wasm_rpc_response.json".to_string());
 
-        log_info!("RPC config: work_dir={}, req={}, resp={}",
-            work_dir, request_file, response_file);
+        let run_id = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| format!("{:x}", d.as_nanos()))
+            .unwrap_or_else(|_| "0".to_string());
+
+        log_info!("RPC config loaded";
+            transport = RPC_TRANSPORT, work_dir = work_dir, request_file = request_file,
+            response_file = response_file, run_id = run_id);
+
+        let policy = RpcPolicy::from_env()?;
+        log_debug!("RPC policy: {:?}", policy);
+
+        let cassette = match cassette_mode {
+            Some(mode) => {
+                let file = env::var("WASM_RPC_CASSETTE")
+                    .unwrap_or_else(|_| "wasm_rpc_cassette.jsonl".to_string());
+                Some(Cassette::open(mode, PathBuf::from(&work_dir).join(file))?)
+            }
+            None => None,
+        };
+
+        let config = Self {
+            work_dir,
+            request_file,
+            response_file,
+            run_id,
+            policy,
+            cassette,
+            started: Instant::now(),
+            calls_made: Cell::new(0),
+        };
+        #[cfg(feature = "file-rpc")]
+        if !config.is_replay() {
+            config.cleanup_stale_files();
+        }
+        Ok(config)
+    }
+
+    /// Config that serves every call from a recorded cassette, without any host
+    pub fn from_cassette(path: impl Into<PathBuf>) -> Result<Self, ModuleError> {
+        Ok(Self {
+            work_dir: ".".to_string(),
+            request_file: "wasm_rpc_request.json".to_string(),
+            response_file: "wasm_rpc_response.json".to_string(),
+            run_id: "replay".to_string(),
+            policy: RpcPolicy::default(),
+            cassette: Some(Cassette::open(CassetteMode::Replay, path.into())?),
+            started: Instant::now(),
+            calls_made: Cell::new(0),
+        })
+    }
+
+    /// Whether responses are served from a cassette instead of the host
+    pub fn is_replay(&self) -> bool {
+        self.cassette.as_ref().is_some_and(|c| c.mode == CassetteMode::Replay)
+    }
+
+    /// Apply typed `rpc` policy overrides, if any
+    pub fn with_overrides(mut self, overrides: Option<&RpcPolicyOverrides>) -> Self {
+        if let Some(overrides) = overrides {
+            self.policy = self.policy.with_overrides(overrides);
+            log_debug!("RPC policy: {:?}", self.policy);
+        }
+        self
+    }
+
+    /// Number of RPC attempts made so far in this run
+    pub fn calls_made(&self) -> u32 {
+        self.calls_made.get()
+    }
+
+    /// Time left before the run deadline, `None` when there is no deadline
+    fn remaining(&self) -> Option<Duration> {
+        self.policy.run_deadline.map(|d| d.saturating_sub(self.started.elapsed()))
+    }
+
+    /// Charge one attempt against the call budget and the run deadline
+    fn begin_call(&self) -> Result<(), ModuleError> {
+        if let Some(max) = self.policy.max_calls {
+            if self.calls_made.get() >= max {
+                return Err(ModuleError::RpcTimeout(format!("RPC call budget exhausted ({} calls per run)", max)));
+            }
+        }
+        if self.remaining() == Some(Duration::ZERO) {
+            return Err(ModuleError::RpcTimeout(format!("RPC run deadline of {}ms exceeded",
+                self.policy.run_deadline.unwrap_or_default().as_millis())));
+        }
+        self.calls_made.set(self.calls_made.get() + 1);
+        Ok(())
+    }
+
+    /// Wait for one attempt: the per-call timeout, cut short by the run deadline
+    fn attempt_timeout(&self) -> Duration {
+        match self.remaining() {
+            Some(left) => left.min(self.policy.call_timeout),
+            None => self.policy.call_timeout,
+        }
+    }
 
-        Ok(Self { work_dir, request_file, response_file })
+    /// Allocate a key that is unique across runs and requests, e.g. `18f3a2c1d-3`
+    pub fn next_request_key(&self) -> String {
+        format!("{}-{}", self.run_id, NEXT_REQUEST_SEQ.fetch_add(1, Ordering::Relaxed))
     }
 
-    pub fn request_path(&self) -> PathBuf {
-        PathBuf::from(&self.work_dir).join(&self.request_file)
+    /// Request file for one call: `wasm_rpc_request.<key>.json`
+    pub fn request_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(&self.work_dir).join(keyed_file_name(&self.request_file, key))
     }
 
-    pub fn response_path(&self) -> PathBuf {
-        PathBuf::from(&self.work_dir).join(&self.response_file)
+    /// Response file for one call: `wasm_rpc_response.<key>.json`
+    pub fn response_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(&self.work_dir).join(keyed_file_name(&self.response_file, key))
+    }
+
+    /// Remove exchange files left behind by timed-out calls or earlier runs
+    ///
+    /// Keyed files are only removed once they are older than any call could
+    /// wait, so other runs sharing the work dir keep their in-flight requests.
+    #[cfg(feature = "file-rpc")]
+    fn cleanup_stale_files(&self) {
+        use std::fs;
+
+        let entries = match fs::read_dir(&self.work_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log_error!("Failed to scan RPC work dir {}: {}", self.work_dir, e);
+                return;
+            }
+        };
+
+        let request_stem = file_stem(&self.request_file);
+        let response_stem = file_stem(&self.response_file);
+        let mut removed = 0;
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(request_stem) && !name.starts_with(response_stem) {
+                continue;
+            }
+
+            let legacy = name == self.request_file || name == self.response_file;
+            let expired = entry.metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age > self.policy.call_timeout);
+
+            if (legacy || expired) && fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            log_info!("Removed {} stale RPC exchange file(s)", removed);
+        }
+    }
+}
+
+fn file_stem(file_name: &str) -> &str {
+    file_name.strip_suffix(".json").unwrap_or(file_name)
+}
+
+fn keyed_file_name(file_name: &str, key: &str) -> String {
+    format!("{}.{}.json", file_stem(file_name), key)
+}
+
+/// Cassette mode, selected with `WASM_RPC_CASSETTE_MODE=record|replay`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Forward to the host and append every request/response pair to the cassette
+    Record,
+    /// Serve responses from the cassette without any host
+    Replay,
+}
+
+impl CassetteMode {
+    fn from_env() -> Result<Option<Self>, ModuleError> {
+        match env::var("WASM_RPC_CASSETTE_MODE").as_deref() {
+            Err(_) | Ok("") | Ok("off") => Ok(None),
+            Ok("record") => Ok(Some(CassetteMode::Record)),
+            Ok("replay") => Ok(Some(CassetteMode::Replay)),
+            Ok(other) => Err(ModuleError::InvalidInput(format!("Invalid WASM_RPC_CASSETTE_MODE: {}", other))),
+        }
+    }
+}
+
+/// JSON-lines log of RPC traffic: one `{"request": .., "response": ..}` object per line
+///
+/// Replay matches requests on their content without the top-level `id` (the
+/// response gets the new request's id); identical requests are served in
+/// recorded order.
+#[derive(Debug)]
+pub struct Cassette {
+    pub mode: CassetteMode,
+    pub path: PathBuf,
+    entries: RefCell<HashMap<String, VecDeque<Value>>>,
+}
+
+impl Cassette {
+    pub fn open(mode: CassetteMode, path: PathBuf) -> Result<Self, ModuleError> {
+        let mut entries: HashMap<String, VecDeque<Value>> = HashMap::new();
+
+        if mode == CassetteMode::Replay {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| ModuleError::InvalidInput(format!("Failed to read cassette {:?}: {}", path, e)))?;
+            for (n, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                let entry: Value = serde_json::from_str(line)
+                    .map_err(|e| ModuleError::InvalidInput(format!("Invalid cassette entry on line {}: {}", n + 1, e)))?;
+                let (Some(request), Some(response)) = (entry.get("request"), entry.get("response")) else {
+                    return Err(ModuleError::InvalidInput(format!("Cassette entry on line {} needs request and response", n + 1)));
+                };
+                entries.entry(Self::key(request)).or_default().push_back(response.clone());
+            }
+            log_info!("Loaded RPC cassette {:?} ({} distinct requests)", path, entries.len());
+        }
+
+        Ok(Self { mode, path, entries: RefCell::new(entries) })
+    }
+
+    fn key(request: &Value) -> String {
+        let mut request = request.clone();
+        if let Some(obj) = request.as_object_mut() {
+            obj.remove("id");
+        }
+        request.to_string()
+    }
+
+    /// Append one exchange to the cassette file
+    fn record(&self, request_str: &str, response_str: &str) {
+        use std::io::Write;
+
+        let entry = match (serde_json::from_str::<Value>(request_str), serde_json::from_str::<Value>(response_str)) {
+            (Ok(request), Ok(response)) => json!({ "request": request, "response": response }),
+            _ => return,
+        };
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| writeln!(f, "{}", entry));
+        if let Err(e) = written {
+            log_error!("Failed to record RPC exchange to {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Serve the next recorded response for this request
+    fn replay(&self, request_str: &str) -> Result<String, ModuleError> {
+        let request: Value = serde_json::from_str(request_str)
+            .map_err(|e| ModuleError::Internal(format!("Invalid request for replay: {}", e)))?;
+
+        let mut response = self.entries.borrow_mut()
+            .get_mut(&Self::key(&request))
+            .and_then(|queue| queue.pop_front())
+            .ok_or_else(|| ModuleError::RpcTransport(
+                format!("No cassette entry for request: {}", &request_str[..request_str.len().min(200)])))?;
+
+        if let (Some(obj), Some(id)) = (response.as_object_mut(), request.get("id")) {
+            obj.insert("id".to_string(), id.clone());
+        }
+        Ok(response.to_string())
     }
 }
 
+/// Name of the transport compiled into this module
+#[cfg(feature = "file-rpc")]
+pub const RPC_TRANSPORT: &str = "file";
+#[cfg(not(feature = "file-rpc"))]
+pub const RPC_TRANSPORT: &str = "host";
+
 /// Make an RPC call to the host
-pub fn rpc_call(config: &RpcConfig, request: &Value) -> Result<Value, String> {
+pub fn rpc_call(config: &RpcConfig, request: &Value) -> Result<Value, ModuleError> {
     let request_str = request.to_string();
-    let request_path = config.request_path();
-    let response_path = config.response_path();
 
     log_debug!("RPC request: {}", &request_str[..request_str.len().min(200)]);
 
-    // Write request file
-    fs::write(&request_path, &request_str)
-        .map_err(|e| format!("Failed to write request to {:?}: {}", request_path, e))?;
+    let response_str = send(config, &request_str)?;
+    finish_call(request, &response_str)
+}
 
-    log_info!("RPC request written, polling for response...");
+/// Parse a single response and check it answers `request`
+fn finish_call(request: &Value, response_str: &str) -> Result<Value, ModuleError> {
+    // Parse JSON response
+    let response: Value = serde_json::from_str(response_str)
+        .map_err(|e| ModuleError::RpcTransport(format!("Failed to parse RPC response: {}", e)))?;
 
-    // Poll for response with timeout
-    let poll_interval = Duration::from_millis(10);
-    let max_wait = Duration::from_secs(10);
-    let mut elapsed = Duration::ZERO;
+    // Check for RPC error
+    if let Some(error) = response.get("error") {
+        return Err(ModuleError::from_rpc_error(error));
+    }
+
+    check_response_id(request, &response)?;
+    Ok(response)
+}
 
-    while elapsed < max_wait {
-        if response_path.exists() {
-            let response_str = fs::read_to_string(&response_path)
-                .map_err(|e| format!("Failed to read response: {}", e))?;
+/// Reject a response whose `id` does not match the request it is read for
+fn check_response_id(request: &Value, response: &Value) -> Result<(), ModuleError> {
+    let expected = request.get("id").unwrap_or(&Value::Null);
+    let actual = response.get("id").unwrap_or(&Value::Null);
+    if expected != actual {
+        return Err(ModuleError::RpcTransport(format!("RPC response id mismatch: expected {}, got {}", expected, actual)));
+    }
+    Ok(())
+}
 
-            log_info!("RPC response received after {}ms", elapsed.as_millis());
+/// Send several requests to the host as one JSON-RPC batch
+///
+/// Responses are matched back to their requests by `id` and returned in request
+/// order. Each element is the full response object: a failed item carries an
+/// `error` field (see [`rpc_result`]) instead of failing the whole batch.
+pub fn rpc_call_batch(config: &RpcConfig, requests: &[Value]) -> Result<Vec<Value>, ModuleError> {
+    if requests.is_empty() {
+        return Ok(Vec::new());
+    }
 
-            // Clean up files
-            let _ = fs::remove_file(&request_path);
-            let _ = fs::remove_file(&response_path);
+    // Renumber items by position so matching does not depend on caller-provided ids
+    let mut batch = Vec::with_capacity(requests.len());
+    for (i, request) in requests.iter().enumerate() {
+        let mut item = request.clone();
+        match item.as_object_mut() {
+            Some(obj) => { obj.insert("id".to_string(), json!(i)); }
+            None => return Err(ModuleError::Internal(format!("Batch item {} is not a JSON-RPC request object", i))),
+        }
+        batch.push(item);
+    }
+    let batch_str = Value::Array(batch).to_string();
+
+    log_debug!("RPC batch request ({} items): {}", requests.len(), &batch_str[..batch_str.len().min(200)]);
+
+    let response_str = send(config, &batch_str)?;
+
+    let response: Value = serde_json::from_str(&response_str)
+        .map_err(|e| ModuleError::RpcTransport(format!("Failed to parse RPC batch response: {}", e)))?;
+
+    match_batch_responses(requests, response)
+}
+
+/// Order batch responses by request position and restore the caller's ids
+fn match_batch_responses(requests: &[Value], response: Value) -> Result<Vec<Value>, ModuleError> {
+    let items = match response {
+        Value::Array(items) => items,
+        // A single error object means the host rejected the batch as a whole
+        other => match other.get("error") {
+            Some(error) => return Err(ModuleError::from_rpc_error(error)),
+            None => return Err(ModuleError::RpcTransport("Expected an array response to RPC batch request".to_string())),
+        },
+    };
 
-            // Parse JSON response
-            let response: Value = serde_json::from_str(&response_str)
-                .map_err(|e| format!("Failed to parse RPC response: {}", e))?;
+    let mut matched: Vec<Option<Value>> = vec![None; requests.len()];
+    for item in items {
+        match item.get("id").and_then(|v| v.as_u64()).map(|i| i as usize) {
+            Some(i) if i < matched.len() && matched[i].is_none() => matched[i] = Some(item),
+            _ => {
+                log_error!("Ignoring RPC batch response with unexpected id: {}", item.get("id").unwrap_or(&Value::Null));
+            }
+        }
+    }
+
+    Ok(matched.into_iter().zip(requests).map(|(item, request)| {
+        let mut item = item.unwrap_or_else(|| json!({
+            "jsonrpc": "2.0",
+            "error": { "code": -32603, "message": "No response for batch item" }
+        }));
+        if let Some(obj) = item.as_object_mut() {
+            obj.insert("id".to_string(), request.get("id").cloned().unwrap_or(Value::Null));
+        }
+        item
+    }).collect())
+}
+
+/// Extract `result` from a JSON-RPC response object, turning `error` into `Err`
+pub fn rpc_result(response: &Value) -> Result<&Value, ModuleError> {
+    if let Some(error) = response.get("error") {
+        return Err(ModuleError::from_rpc_error(error));
+    }
+    response.get("result")
+        .ok_or_else(|| ModuleError::AbiDecode("No result in RPC response".to_string()))
+}
+
+fn send(config: &RpcConfig, request: &str) -> Result<String, ModuleError> {
+    send_many(config, &[request.to_string()]).pop()
+        .unwrap_or_else(|| Err(ModuleError::Internal("No RPC response".to_string())))
+}
+
+/// Exchange raw requests under the config's [`RpcPolicy`]
+///
+/// Attempts that time out or come back with a transient JSON-RPC error are
+/// retried with exponential backoff while retries, call budget and run
+/// deadline allow; the last outcome of every request is returned.
+fn send_many(config: &RpcConfig, requests: &[String]) -> Vec<Result<String, ModuleError>> {
+    let mut results: Vec<Option<Result<String, ModuleError>>> = vec![None; requests.len()];
+    let mut todo: Vec<usize> = (0..requests.len()).collect();
+    let mut attempt = 0;
+
+    loop {
+        let mut in_flight = Vec::with_capacity(todo.len());
+        for i in todo.drain(..) {
+            match config.begin_call() {
+                Ok(()) => in_flight.push(i),
+                // Keep the previous attempt's outcome if there was one
+                Err(e) => if results[i].is_none() { results[i] = Some(Err(e)) },
+            }
+        }
+        if in_flight.is_empty() {
+            break;
+        }
+
+        let batch: Vec<String> = in_flight.iter().map(|&i| requests[i].clone()).collect();
+        for (i, outcome) in in_flight.into_iter().zip(transport_many(config, &batch)) {
+            if attempt < config.policy.max_retries && !config.is_replay() && is_retryable(&outcome) {
+                todo.push(i);
+            }
+            results[i] = Some(outcome);
+        }
+        if todo.is_empty() {
+            break;
+        }
+
+        let delay = config.policy.backoff_for(attempt);
+        if config.remaining().is_some_and(|left| left <= delay) {
+            log_warn!("RPC run deadline reached, not retrying"; pending = todo.len());
+            break;
+        }
+        attempt += 1;
+        log_warn!("Retrying RPC request(s)";
+            pending = todo.len(), delay_ms = delay.as_millis() as u64,
+            attempt = attempt, max_retries = config.policy.max_retries);
+        thread::sleep(delay);
+    }
+
+    results.into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(ModuleError::Internal("No RPC response".to_string()))))
+        .collect()
+}
 
-            // Check for RPC error
-            if let Some(error) = response.get("error") {
-                return Err(format!("RPC error: {}", error));
+/// Exchange with the host, or with the cassette when recording/replaying
+fn transport_many(config: &RpcConfig, requests: &[String]) -> Vec<Result<String, ModuleError>> {
+    match &config.cassette {
+        Some(cassette) if cassette.mode == CassetteMode::Replay => {
+            requests.iter().map(|request| cassette.replay(request)).collect()
+        }
+        Some(cassette) => {
+            let outcomes = exchange_many(config, requests);
+            for (request, outcome) in requests.iter().zip(&outcomes) {
+                if let Ok(response) = outcome {
+                    cassette.record(request, response);
+                }
             }
+            outcomes
+        }
+        None => exchange_many(config, requests),
+    }
+}
+
+/// Transport failures (timeouts included) and transient JSON-RPC errors are retryable
+fn is_retryable(outcome: &Result<String, ModuleError>) -> bool {
+    match outcome {
+        Err(e) => e.is_retryable(),
+        Ok(response_str) => serde_json::from_str::<Value>(response_str).ok()
+            .and_then(|response| response.get("error")?.get("code")?.as_i64())
+            .is_some_and(|code| TRANSIENT_RPC_CODES.contains(&code)),
+    }
+}
+
+/// Send a raw request through `host.rpc_call` and copy the response out of guest memory
+///
+/// The import is synchronous; the host enforces the attempt timeout it is passed.
+#[cfg(not(feature = "file-rpc"))]
+fn exchange(config: &RpcConfig, request: &str) -> Result<String, ModuleError> {
+    let timeout = config.attempt_timeout();
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(HOST_RPC_TIMED_OUT - 1);
+        let mut resp_ptr: u32 = 0;
+        let resp_len = unsafe {
+            host_rpc_call(
+                request.as_ptr() as u32,
+                request.len() as u32,
+                &mut resp_ptr as *mut u32 as u32,
+                timeout_ms,
+            )
+        };
+
+        if resp_len == HOST_RPC_TIMED_OUT {
+            return Err(ModuleError::RpcTimeout(format!("RPC call timeout after {}ms", timeout.as_millis())));
+        }
+        if resp_len == 0 || resp_ptr == 0 {
+            return Err(ModuleError::RpcTransport("host.rpc_call returned no response".to_string()));
+        }
+
+        // The host allocated the buffer through our `alloc` export; copy it out and release it.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(resp_ptr as *const u8, resp_len as usize).to_vec()
+        };
+        dealloc(resp_ptr as *mut u8, resp_len);
+
+        log_info!("RPC response received from host ({} bytes)", resp_len);
+
+        String::from_utf8(bytes)
+            .map_err(|e| ModuleError::RpcTransport(format!("RPC response is not valid UTF-8: {}", e)))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = (request, timeout);
+        Err(ModuleError::RpcTransport("host.rpc_call import is only available on wasm32 targets".to_string()))
+    }
+}
+
+/// The host import is synchronous, so calls simply run one after another
+#[cfg(not(feature = "file-rpc"))]
+fn exchange_many(config: &RpcConfig, requests: &[String]) -> Vec<Result<String, ModuleError>> {
+    requests.iter().map(|request| exchange(config, request)).collect()
+}
+
+/// Write one keyed request file per request and poll for the matching response files
+#[cfg(feature = "file-rpc")]
+fn exchange_many(config: &RpcConfig, requests: &[String]) -> Vec<Result<String, ModuleError>> {
+    use std::fs;
+
+    let mut results: Vec<Option<Result<String, ModuleError>>> = vec![None; requests.len()];
+    let mut pending: Vec<(usize, PathBuf, PathBuf)> = Vec::new();
+
+    for (i, request) in requests.iter().enumerate() {
+        let key = config.next_request_key();
+        let request_path = config.request_path(&key);
+        let response_path = config.response_path(&key);
 
-            return Ok(response);
+        // Write under a temporary name first so the host never claims a partial request
+        let tmp_path = request_path.with_extension("json.tmp");
+        match fs::write(&tmp_path, request).and_then(|_| fs::rename(&tmp_path, &request_path)) {
+            Ok(()) => pending.push((i, request_path, response_path)),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                results[i] = Some(Err(ModuleError::RpcTransport(format!("Failed to write request to {:?}: {}", request_path, e))));
+            }
+        }
+    }
+
+    log_info!("{} RPC request(s) written, polling for responses...", pending.len());
+
+    // Poll for responses with timeout
+    let poll_interval = config.policy.poll_interval;
+    let max_wait = config.attempt_timeout();
+    let mut elapsed = Duration::ZERO;
+
+    while !pending.is_empty() && elapsed < max_wait {
+        pending.retain(|(i, request_path, response_path)| {
+            if !response_path.exists() {
+                return true;
+            }
+
+            results[*i] = Some(fs::read_to_string(response_path)
+                .map_err(|e| ModuleError::RpcTransport(format!("Failed to read response: {}", e))));
+
+            // Clean up files
+            let _ = fs::remove_file(request_path);
+            let _ = fs::remove_file(response_path);
+            false
+        });
+
+        if pending.is_empty() {
+            log_info!("RPC response(s) received after {}ms", elapsed.as_millis());
+            break;
         }
 
         thread::sleep(poll_interval);
@@ -136,8 +949,393 @@ pub fn rpc_call(config: &RpcConfig, request: &Value) -> Result<Value, String> {
     }
 
     // Timeout
-    let _ = fs::remove_file(&request_path);
-    Err(format!("RPC call timeout after {}s", max_wait.as_secs()))
+    for (i, request_path, _) in pending {
+        let _ = fs::remove_file(&request_path);
+        results[i] = Some(Err(ModuleError::RpcTimeout(format!("RPC call timeout after {}ms", max_wait.as_millis()))));
+    }
+
+    results.into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(ModuleError::Internal("No RPC response".to_string()))))
+        .collect()
+}
+
+// ============================================================================
+// Typed Ethereum Client
+// ============================================================================
+
+/// Block selector for state-reading calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockTag {
+    Latest,
+    Number(u64),
+    /// EIP-1898 block hash selector
+    Hash(H256),
+}
+
+impl BlockTag {
+    /// Read a pinned block from the input's `blockHash` or `blockNumber` field
+    ///
+    /// `blockHash` wins when both are given since it also survives reorgs.
+    /// `blockNumber` accepts a JSON number, a decimal string or a 0x-prefixed hex string.
+    pub fn from_input(input: &Value) -> Result<Option<BlockTag>, ModuleError> {
+        let hash = match input.get("blockHash").filter(|v| !v.is_null()) {
+            Some(hash) => Some(hash.as_str()
+                .ok_or_else(|| ModuleError::InvalidInput("blockHash must be a hex string".to_string()))?),
+            None => None,
+        };
+        Self::parse(input.get("blockNumber"), hash)
+    }
+
+    /// Parse already-extracted `blockNumber` / `blockHash` input fields, see [`BlockTag::from_input`]
+    pub fn parse(block_number: Option<&Value>, block_hash: Option<&str>) -> Result<Option<BlockTag>, ModuleError> {
+        if let Some(hash) = block_hash {
+            let hash: H256 = hash.strip_prefix("0x").unwrap_or(hash).parse()
+                .map_err(|e| ModuleError::InvalidInput(format!("Invalid blockHash: {}", e)))?;
+            return Ok(Some(BlockTag::Hash(hash)));
+        }
+
+        let number = match block_number {
+            None | Some(Value::Null) => return Ok(None),
+            Some(Value::Number(n)) => n.as_u64()
+                .ok_or_else(|| ModuleError::InvalidInput(format!("Invalid blockNumber: {}", n)))?,
+            Some(Value::String(s)) => match s.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => s.parse(),
+            }.map_err(|e| ModuleError::InvalidInput(format!("Invalid blockNumber {}: {}", s, e)))?,
+            Some(other) => return Err(ModuleError::InvalidInput(format!("Invalid blockNumber: {}", other))),
+        };
+        Ok(Some(BlockTag::Number(number)))
+    }
+
+    pub fn number(&self) -> Option<u64> {
+        match self {
+            BlockTag::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn hash(&self) -> Option<H256> {
+        match self {
+            BlockTag::Hash(h) => Some(*h),
+            _ => None,
+        }
+    }
+
+    pub fn to_param(&self) -> Value {
+        match self {
+            BlockTag::Latest => json!("latest"),
+            BlockTag::Number(n) => json!(format!("0x{:x}", n)),
+            BlockTag::Hash(h) => json!({ "blockHash": format!("{:?}", h) }),
+        }
+    }
+}
+
+/// Filter for `eth_getLogs`
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    pub addresses: Vec<Address>,
+    /// Topic positions; `None` matches any value
+    pub topics: Vec<Option<H256>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Log {
+    pub address: Address,
+    pub topics: Vec<H256>,
+    pub data: Vec<u8>,
+    pub block_number: Option<u64>,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub number: u64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    pub timestamp: u64,
+    pub gas_limit: U256,
+    pub base_fee_per_gas: Option<U256>,
+}
+
+/// One call of an [`EthClient::batch`]; decode its result with the matching
+/// `parse_*` function
+#[derive(Debug, Clone, PartialEq)]
+pub struct EthRequest {
+    pub method: &'static str,
+    pub params: Value,
+}
+
+impl EthRequest {
+    pub fn call(to: Address, data: &[u8], block: BlockTag) -> Self {
+        Self {
+            method: "eth_call",
+            params: json!([{
+                "to": format!("{:?}", to),
+                "data": format!("0x{}", hex::encode(data))
+            }, block.to_param()]),
+        }
+    }
+
+    pub fn gas_price() -> Self {
+        Self { method: "eth_gasPrice", params: json!([]) }
+    }
+
+    /// Block header request; decode with [`parse_block_result`]
+    pub fn get_block(block: BlockTag) -> Self {
+        match block {
+            BlockTag::Hash(h) => Self { method: "eth_getBlockByHash", params: json!([format!("{:?}", h), false]) },
+            _ => Self { method: "eth_getBlockByNumber", params: json!([block.to_param(), false]) },
+        }
+    }
+}
+
+/// Typed JSON-RPC client over [`rpc_call`]'s transport
+pub struct EthClient<'a> {
+    config: &'a RpcConfig,
+    chain_id: u64,
+    block: BlockTag,
+    next_id: Cell<u64>,
+}
+
+impl<'a> EthClient<'a> {
+    pub fn new(config: &'a RpcConfig, chain_id: u64) -> Self {
+        Self { config, chain_id, block: BlockTag::Latest, next_id: Cell::new(1) }
+    }
+
+    /// Pin the block that state reads of this run should use
+    pub fn with_block(mut self, block: BlockTag) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Block every state read of this run is pinned to (`Latest` until pinned)
+    pub fn block(&self) -> BlockTag {
+        self.block
+    }
+
+    /// Pin the requested block, or resolve the current head once
+    pub fn pin_block(self, block: Option<BlockTag>) -> Result<Self, ModuleError> {
+        let block = match block {
+            Some(block) => block,
+            None => BlockTag::Number(self.eth_block_number()?),
+        };
+        log_info!("Pinned RPC reads to block {:?}", block; block_number = block.number(), block_hash = block.hash());
+        Ok(self.with_block(block))
+    }
+
+    /// JSON-RPC request object with the next id of this client
+    fn envelope(&self, method: &str, params: Value) -> Value {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "chainId": self.chain_id,
+            "params": params
+        })
+    }
+
+    /// Send a request and return its `result`, keeping JSON-RPC error codes
+    pub fn request(&self, method: &str, params: Value) -> Result<Value, ModuleError> {
+        let request = self.envelope(method, params);
+        let request_str = request.to_string();
+
+        log_debug!("RPC request: {}", &request_str[..request_str.len().min(200)]);
+
+        let response_str = send(self.config, &request_str)?;
+        let mut response: Value = serde_json::from_str(&response_str)
+            .map_err(|e| ModuleError::RpcTransport(format!("Failed to parse RPC response: {}", e)))?;
+
+        if let Some(error) = response.get("error") {
+            return Err(ModuleError::from_rpc_error(error));
+        }
+        check_response_id(&request, &response)?;
+        match response.get_mut("result") {
+            Some(result) => Ok(result.take()),
+            None => Err(ModuleError::AbiDecode(format!("No result in {} response", method))),
+        }
+    }
+
+    /// Send several requests in one round-trip and return each `result` in order
+    ///
+    /// A failed item is an `Err` of its own; the outer `Err` means the batch as a
+    /// whole got no answer. A single request is sent on its own, not as a batch.
+    pub fn batch(&self, requests: &[EthRequest]) -> Result<Vec<Result<Value, ModuleError>>, ModuleError> {
+        if let [request] = requests {
+            return Ok(vec![self.request(request.method, request.params.clone())]);
+        }
+        let batch: Vec<Value> = requests.iter()
+            .map(|request| self.envelope(request.method, request.params.clone()))
+            .collect();
+        Ok(rpc_call_batch(self.config, &batch)?.iter()
+            .map(|response| rpc_result(response).cloned())
+            .collect())
+    }
+
+    pub fn eth_call(&self, to: Address, data: &[u8], block: BlockTag) -> Result<Vec<u8>, ModuleError> {
+        let EthRequest { method, params } = EthRequest::call(to, data, block);
+        parse_bytes(&self.request(method, params)?)
+    }
+
+    pub fn eth_gas_price(&self) -> Result<U256, ModuleError> {
+        let EthRequest { method, params } = EthRequest::gas_price();
+        parse_u256(&self.request(method, params)?)
+    }
+
+    /// Gas a call from `from` would use against the state of `block`
+    ///
+    /// `block` is a number or `Latest`: many nodes reject the EIP-1898 block hash
+    /// selector for `eth_estimateGas`.
+    pub fn eth_estimate_gas(&self, from: Option<Address>, to: Address, data: &[u8], block: BlockTag) -> Result<u64, ModuleError> {
+        if let BlockTag::Hash(h) = block {
+            return Err(ModuleError::Internal(format!("eth_estimateGas takes a block number, not hash {:?}", h)));
+        }
+        let mut call = json!({
+            "to": format!("{:?}", to),
+            "data": format!("0x{}", hex::encode(data))
+        });
+        if let Some(from) = from {
+            call["from"] = json!(format!("{:?}", from));
+        }
+        parse_u64(&self.request("eth_estimateGas", json!([call, block.to_param()]))?)
+    }
+
+    pub fn eth_get_balance(&self, address: Address, block: BlockTag) -> Result<U256, ModuleError> {
+        let result = self.request("eth_getBalance", json!([format!("{:?}", address), block.to_param()]))?;
+        parse_u256(&result)
+    }
+
+    pub fn eth_block_number(&self) -> Result<u64, ModuleError> {
+        parse_u64(&self.request("eth_blockNumber", json!([]))?)
+    }
+
+    pub fn eth_chain_id(&self) -> Result<u64, ModuleError> {
+        parse_u64(&self.request("eth_chainId", json!([]))?)
+    }
+
+    pub fn eth_get_storage_at(&self, address: Address, slot: U256, block: BlockTag) -> Result<H256, ModuleError> {
+        let result = self.request("eth_getStorageAt", json!([
+            format!("{:?}", address),
+            format!("0x{:x}", slot),
+            block.to_param()
+        ]))?;
+        parse_h256(&result)
+    }
+
+    pub fn eth_get_logs(&self, filter: &LogFilter) -> Result<Vec<Log>, ModuleError> {
+        let mut params = serde_json::Map::new();
+        if let Some(from) = filter.from_block {
+            params.insert("fromBlock".to_string(), json!(format!("0x{:x}", from)));
+        }
+        if let Some(to) = filter.to_block {
+            params.insert("toBlock".to_string(), json!(format!("0x{:x}", to)));
+        }
+        if !filter.addresses.is_empty() {
+            let addresses: Vec<String> = filter.addresses.iter().map(|a| format!("{:?}", a)).collect();
+            params.insert("address".to_string(), json!(addresses));
+        }
+        if !filter.topics.is_empty() {
+            let topics: Vec<Value> = filter.topics.iter()
+                .map(|t| t.map(|h| json!(format!("{:?}", h))).unwrap_or(Value::Null))
+                .collect();
+            params.insert("topics".to_string(), Value::Array(topics));
+        }
+
+        let result = self.request("eth_getLogs", json!([Value::Object(params)]))?;
+        result.as_array()
+            .ok_or_else(|| ModuleError::AbiDecode("eth_getLogs result is not an array".to_string()))?
+            .iter()
+            .map(parse_log)
+            .collect()
+    }
+
+    /// Fetch a block header; `None` when the node does not know the block
+    pub fn eth_get_block(&self, block: BlockTag) -> Result<Option<Block>, ModuleError> {
+        let EthRequest { method, params } = EthRequest::get_block(block);
+        parse_block_result(&self.request(method, params)?)
+    }
+}
+
+/// Block header result; `None` when the node does not know the block
+pub fn parse_block_result(result: &Value) -> Result<Option<Block>, ModuleError> {
+    if result.is_null() {
+        return Ok(None);
+    }
+    parse_block(result).map(Some)
+}
+
+fn field<'v>(value: &'v Value, name: &str) -> Result<&'v Value, ModuleError> {
+    value.get(name).ok_or_else(|| ModuleError::AbiDecode(format!("Missing field {}", name)))
+}
+
+fn hex_str(value: &Value) -> Result<&str, ModuleError> {
+    let s = value.as_str()
+        .ok_or_else(|| ModuleError::AbiDecode(format!("Expected hex string, got {}", value)))?;
+    Ok(s.strip_prefix("0x").unwrap_or(s))
+}
+
+pub fn parse_bytes(value: &Value) -> Result<Vec<u8>, ModuleError> {
+    hex::decode(hex_str(value)?).map_err(|e| ModuleError::AbiDecode(format!("Invalid hex data: {}", e)))
+}
+
+fn parse_u256(value: &Value) -> Result<U256, ModuleError> {
+    U256::from_str_radix(hex_str(value)?, 16)
+        .map_err(|e| ModuleError::AbiDecode(format!("Invalid quantity {}: {}", value, e)))
+}
+
+fn parse_u64(value: &Value) -> Result<u64, ModuleError> {
+    u64::from_str_radix(hex_str(value)?, 16)
+        .map_err(|e| ModuleError::AbiDecode(format!("Invalid quantity {}: {}", value, e)))
+}
+
+fn parse_h256(value: &Value) -> Result<H256, ModuleError> {
+    hex_str(value)?.parse()
+        .map_err(|e| ModuleError::AbiDecode(format!("Invalid 32-byte hash {}: {}", value, e)))
+}
+
+fn parse_address(value: &Value) -> Result<Address, ModuleError> {
+    hex_str(value)?.parse()
+        .map_err(|e| ModuleError::AbiDecode(format!("Invalid address {}: {}", value, e)))
+}
+
+fn parse_optional<T>(value: &Value, name: &str, parse: fn(&Value) -> Result<T, ModuleError>) -> Result<Option<T>, ModuleError> {
+    match value.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => parse(v).map(Some),
+    }
+}
+
+fn parse_log(value: &Value) -> Result<Log, ModuleError> {
+    let topics = field(value, "topics")?.as_array()
+        .ok_or_else(|| ModuleError::AbiDecode("Log topics is not an array".to_string()))?
+        .iter()
+        .map(parse_h256)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Log {
+        address: parse_address(field(value, "address")?)?,
+        topics,
+        data: parse_bytes(field(value, "data")?)?,
+        block_number: parse_optional(value, "blockNumber", parse_u64)?,
+        transaction_hash: parse_optional(value, "transactionHash", parse_h256)?,
+        log_index: parse_optional(value, "logIndex", parse_u64)?,
+    })
+}
+
+fn parse_block(value: &Value) -> Result<Block, ModuleError> {
+    Ok(Block {
+        number: parse_u64(field(value, "number")?)?,
+        hash: parse_h256(field(value, "hash")?)?,
+        parent_hash: parse_h256(field(value, "parentHash")?)?,
+        timestamp: parse_u64(field(value, "timestamp")?)?,
+        gas_limit: parse_u256(field(value, "gasLimit")?)?,
+        base_fee_per_gas: parse_optional(value, "baseFeePerGas", parse_u256)?,
+    })
 }
 
 // ============================================================================
@@ -154,36 +1352,476 @@ pub const PROTO_MORPHO: u8 = 4;
 // Output Helpers
 // ============================================================================
 
-/// Output success result with skip flag
-pub fn output_success(result: Value) {
-    println!("{}", json!({
-        "ok": true,
-        "result": result
-    }));
-}
-
-/// Output success result with skip remaining steps flag
-pub fn output_skip(message: &str) {
-    println!("{}", json!({
-        "ok": true,
-        "result": {
-            "ok": true,
-            "success": true,
-            "skipRemainingSteps": true,
-            "message": message
-        }
-    }));
-}
-
-/// Output error result
-pub fn output_error(error: &str) {
-    log_error!("{}", error);
-    println!("{}", json!({
-        "ok": false,
-        "result": {
-            "ok": false,
-            "success": false,
-            "error": error
-        }
-    }));
+/// Version of the output envelope layout; bumped on breaking changes
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Decimal places floats are rounded to in deterministic output
+pub const FLOAT_DECIMALS: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct ModuleInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+pub const MODULE: ModuleInfo = ModuleInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+};
+
+/// Every line the module prints to stdout
+///
+/// `result` is the action's payload on success, or [`ModuleError::to_result`] on failure.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope<T> {
+    pub ok: bool,
+    pub schema_version: u32,
+    pub module: ModuleInfo,
+    pub action: String,
+    /// Keccak-256 of the canonical (key-sorted) input JSON
+    pub input_hash: String,
+    pub result: T,
+    /// Wall-clock metrics; not part of the consensus result and omitted in deterministic mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<Telemetry>,
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn new(action: &str, input: &Value, ok: bool, result: T) -> Self {
+        Self {
+            ok,
+            schema_version: SCHEMA_VERSION,
+            module: MODULE,
+            action: action.to_string(),
+            input_hash: input_hash(input),
+            result,
+            telemetry: None,
+        }
+    }
+
+    pub fn with_telemetry(mut self, telemetry: Option<Telemetry>) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+}
+
+/// Metrics that differ from run to run even on identical input
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Telemetry {
+    /// Wall-clock time of the action
+    pub time_ms: f64,
+}
+
+impl Telemetry {
+    pub fn since(started: Instant) -> Self {
+        Self { time_ms: started.elapsed().as_secs_f64() * 1000.0 }
+    }
+}
+
+/// Identify an input independently of key order and whitespace
+///
+/// Hashes [`canonical_input`] rather than [`canonical_json`], so inputs that
+/// differ only past [`FLOAT_DECIMALS`] still get different hashes.
+pub fn input_hash(input: &Value) -> String {
+    format!("0x{}", hex::encode(Keccak256::digest(canonical_input(input).as_bytes())))
+}
+
+/// Payload of a step that wants the workflow to stop here
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SkipResult {
+    pub ok: bool,
+    pub success: bool,
+    pub skip_remaining_steps: bool,
+    pub message: String,
+}
+
+impl SkipResult {
+    pub fn new(message: &str) -> Self {
+        Self { ok: true, success: true, skip_remaining_steps: true, message: message.to_string() }
+    }
+}
+
+/// Whether the output must be byte-identical across runs and hosts: the input
+/// `deterministic` field, or `WASM_DETERMINISTIC=1` when it is absent
+pub fn deterministic_output(input: &Value) -> bool {
+    input.get("deterministic")
+        .and_then(|v| v.as_bool())
+        .unwrap_or_else(|| matches!(env::var("WASM_DETERMINISTIC").as_deref(), Ok("1") | Ok("true")))
+}
+
+/// The output line for the outcome of `action`: its [`Envelope`] as JSON
+///
+/// In deterministic mode the telemetry goes to the log instead of the output,
+/// so the line only depends on the input and the chain state.
+pub fn output<T: Serialize>(action: &str, input: &Value, outcome: Result<T, ModuleError>, telemetry: Option<Telemetry>) -> String {
+    let deterministic = deterministic_output(input);
+    if let (true, Some(telemetry)) = (deterministic, telemetry) {
+        log_info!("Run telemetry"; action = action, time_ms = telemetry.time_ms);
+    }
+    render(action, input, outcome, telemetry, deterministic)
+}
+
+/// Serialize the envelope of `outcome`; in deterministic mode canonically and
+/// without telemetry
+pub fn render<T: Serialize>(
+    action: &str,
+    input: &Value,
+    outcome: Result<T, ModuleError>,
+    telemetry: Option<Telemetry>,
+    deterministic: bool,
+) -> String {
+    let telemetry = telemetry.filter(|_| !deterministic);
+    let envelope = match outcome {
+        Ok(result) => serde_json::to_value(Envelope::new(action, input, true, result).with_telemetry(telemetry))
+            .map_err(|e| ModuleError::Internal(format!("Failed to serialize result: {}", e))),
+        Err(e) => Err(e),
+    };
+    let envelope = envelope.unwrap_or_else(|error| {
+        log_error!("{}", error; code = error.code(), retryable = error.is_retryable());
+        json!(Envelope::new(action, input, false, error.to_result()).with_telemetry(telemetry))
+    });
+    if deterministic {
+        canonical_json(&envelope)
+    } else {
+        envelope.to_string()
+    }
+}
+
+/// Compact JSON with object keys sorted and floats printed by [`format_float`],
+/// independent of serde_json's map ordering features
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, true, &mut out);
+    out
+}
+
+/// Compact JSON with object keys sorted and numbers kept as parsed: integers
+/// exactly, floats in their shortest round-trip form
+pub fn canonical_input(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, false, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, round_floats: bool, out: &mut String) {
+    match value {
+        Value::Number(n) if round_floats && n.is_f64() => out.push_str(&format_float(n.as_f64().unwrap_or(0.0))),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, round_floats, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(&map[key], round_floats, out);
+            }
+            out.push('}');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// Round to [`FLOAT_DECIMALS`] places and print in plain decimal notation
+/// without trailing zeros, so last-bit differences and exponent formatting
+/// never reach the output
+pub fn format_float(value: f64) -> String {
+    let fixed = format!("{:.*}", FLOAT_DECIMALS, value);
+    let trimmed = fixed.trim_end_matches('0').trim_end_matches('.');
+    match trimmed {
+        "-0" => "0".to_string(),
+        _ => trimmed.to_string(),
+    }
+}
+
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_directives_filter_by_level_and_target() {
+        let default = LogDirectives::default();
+        assert!(default.enabled(LogLevel::Info, "rebalance_wasm::rebalance"));
+        assert!(!default.enabled(LogLevel::Debug, "rebalance_wasm::rebalance"));
+
+        let directives = LogDirectives::parse("warn, rebalance_wasm::common=debug, rebalance_wasm::multicall=off").unwrap();
+        assert!(directives.enabled(LogLevel::Debug, "rebalance_wasm::common"));
+        assert!(!directives.enabled(LogLevel::Trace, "rebalance_wasm::common"));
+        assert!(!directives.enabled(LogLevel::Error, "rebalance_wasm::multicall"));
+        assert!(!directives.enabled(LogLevel::Info, "rebalance_wasm::rebalance"));
+        assert!(directives.enabled(LogLevel::Warn, "rebalance_wasm::rebalance"));
+        // Prefixes match whole path segments only
+        assert!(!directives.enabled(LogLevel::Debug, "rebalance_wasm::commonly"));
+
+        assert!(!LogDirectives::parse("off").unwrap().enabled(LogLevel::Error, "rebalance_wasm"));
+        assert!(LogDirectives::parse("verbose").is_err());
+    }
+
+    #[test]
+    fn test_log_record_shape_and_sequence() {
+        let first = log_record(LogLevel::Warn, "rebalance_wasm::common", "Retrying", vec![("attempt", log_field(&2))]);
+        let second = log_record(LogLevel::Info, "rebalance_wasm::common", "Done", Vec::new());
+
+        assert_eq!(first["level"], "warn");
+        assert_eq!(first["target"], "rebalance_wasm::common");
+        assert_eq!(first["msg"], "Retrying");
+        assert_eq!(first["fields"], json!({"attempt": 2}));
+        assert!(second.get("fields").is_none());
+        assert!(second["seq"].as_u64().unwrap() > first["seq"].as_u64().unwrap());
+        // One record per line
+        assert!(!first.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn test_envelope_layout_and_input_hash() {
+        let input = json!({"action": "rebalance", "config": {"stepPct": 1, "maxPoolShare": 0.2}});
+        let reordered: Value = serde_json::from_str(
+            r#"{ "config": { "maxPoolShare": 0.2, "stepPct": 1 }, "action": "rebalance" }"#).unwrap();
+        assert_eq!(input_hash(&input), input_hash(&reordered));
+        assert_ne!(input_hash(&input), input_hash(&json!({"action": "rebalance"})));
+        assert_eq!(input_hash(&input).len(), 66);
+        let canonical = format!("0x{}", hex::encode(Keccak256::digest(br#"{"action":"rebalance","config":{"maxPoolShare":0.2,"stepPct":1}}"#)));
+        assert_eq!(input_hash(&input), canonical);
+
+        // Rounding is for rendering only: a difference past FLOAT_DECIMALS still changes the hash
+        let precise = json!({"apy": 0.0412345678901});
+        assert_eq!(canonical_json(&precise), canonical_json(&json!({"apy": 0.0412345678902})));
+        assert_ne!(input_hash(&precise), input_hash(&json!({"apy": 0.0412345678902})));
+        assert_eq!(canonical_input(&precise), r#"{"apy":0.0412345678901}"#);
+
+        let envelope = json!(Envelope::new("rebalance", &input, true, SkipResult::new("nothing to do")));
+        assert_eq!(envelope["ok"], true);
+        assert_eq!(envelope["schemaVersion"], SCHEMA_VERSION);
+        assert_eq!(envelope["module"], json!({"name": "rebalance-wasm", "version": env!("CARGO_PKG_VERSION")}));
+        assert_eq!(envelope["action"], "rebalance");
+        assert_eq!(envelope["inputHash"], json!(input_hash(&input)));
+        assert_eq!(envelope["result"]["skipRemainingSteps"], true);
+        assert!(envelope.get("telemetry").is_none());
+    }
+
+    #[test]
+    fn test_deterministic_render_is_canonical_and_drops_telemetry() {
+        let input = json!({"action": "rebalance", "deterministic": true});
+        assert!(deterministic_output(&input));
+        assert!(!deterministic_output(&json!({"deterministic": false})));
+
+        let result = || Ok(json!({"zeta": [0.1 + 0.2, 1e-12, -1e-12, 2.5e21], "alpha": 12, "mid": 100.0}));
+        let first = render("rebalance", &input, result(), Some(Telemetry { time_ms: 1.5 }), true);
+        let second = render("rebalance", &input, result(), Some(Telemetry { time_ms: 7.25 }), true);
+        assert_eq!(first, second);
+        assert!(!first.contains("telemetry"));
+        assert!(first.contains(r#""result":{"alpha":12,"mid":100,"zeta":[0.3,0,0,2500000000000000000000]}"#), "{}", first);
+        assert!(first.starts_with(r#"{"action":"rebalance","inputHash":"0x"#));
+
+        let timed: Value = serde_json::from_str(
+            &render("rebalance", &input, result(), Some(Telemetry { time_ms: 1.5 }), false)).unwrap();
+        assert_eq!(timed["telemetry"], json!({"timeMs": 1.5}));
+    }
+
+    #[test]
+    fn test_batch_responses_matched_by_id() {
+        let requests = vec![
+            json!({"jsonrpc": "2.0", "id": "snapshot", "method": "eth_call", "params": []}),
+            json!({"jsonrpc": "2.0", "id": 7, "method": "eth_blockNumber", "params": []}),
+            json!({"jsonrpc": "2.0", "method": "eth_gasPrice", "params": []}),
+        ];
+        // Host answers out of order, with one per-item error and one missing item
+        let response = json!([
+            {"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "boom"}},
+            {"jsonrpc": "2.0", "id": 0, "result": "0xabc"},
+        ]);
+
+        let results = match_batch_responses(&requests, response).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["id"], json!("snapshot"));
+        assert_eq!(rpc_result(&results[0]).unwrap(), &json!("0xabc"));
+        assert_eq!(results[1]["id"], json!(7));
+        assert_eq!(rpc_result(&results[1]).unwrap_err(),
+            ModuleError::RpcError { code: -32000, message: "boom".to_string() });
+        assert_eq!(results[2]["error"]["code"], json!(-32603));
+    }
+
+    #[test]
+    fn test_batch_rejected_as_a_whole() {
+        let requests = vec![json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"})];
+        let response = json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "Invalid Request"}});
+
+        assert!(match_batch_responses(&requests, response).is_err());
+    }
+
+    #[test]
+    fn test_response_id_must_match_request() {
+        let request = json!({"jsonrpc": "2.0", "id": 5, "method": "eth_blockNumber"});
+
+        assert!(finish_call(&request, r#"{"jsonrpc":"2.0","id":5,"result":"0x1"}"#).is_ok());
+        let stale = finish_call(&request, r#"{"jsonrpc":"2.0","id":4,"result":"0x1"}"#);
+        assert!(matches!(stale, Err(ModuleError::RpcTransport(msg)) if msg.contains("id mismatch")));
+    }
+
+    #[test]
+    fn test_request_keys_are_unique_per_call() {
+        let config = RpcConfig {
+            work_dir: "/tmp/rpc".to_string(),
+            request_file: "wasm_rpc_request.json".to_string(),
+            response_file: "wasm_rpc_response.json".to_string(),
+            run_id: "abc".to_string(),
+            policy: RpcPolicy::default(),
+            cassette: None,
+            started: Instant::now(),
+            calls_made: Cell::new(0),
+        };
+
+        let first = config.next_request_key();
+        let second = config.next_request_key();
+        assert_ne!(first, second);
+        assert!(first.starts_with("abc-"));
+        assert_eq!(
+            config.response_path(&first),
+            PathBuf::from(format!("/tmp/rpc/wasm_rpc_response.{}.json", first))
+        );
+    }
+
+    #[test]
+    fn test_rpc_policy_from_input_and_budget() {
+        let overrides: RpcPolicyOverrides = serde_json::from_value(
+            json!({"timeoutMs": 2500, "maxRetries": 3, "backoffMs": 50, "maxCalls": 2})
+        ).unwrap();
+        let policy = RpcPolicy::default().with_overrides(&overrides);
+
+        assert_eq!(policy.call_timeout, Duration::from_millis(2500));
+        assert_eq!(policy.max_retries, 3);
+        assert_eq!(policy.backoff_for(0), Duration::from_millis(50));
+        assert_eq!(policy.backoff_for(2), Duration::from_millis(200));
+        assert!(serde_json::from_value::<RpcPolicyOverrides>(json!({"timeoutMs": "soon"})).is_err());
+
+        let config = RpcConfig {
+            work_dir: ".".to_string(),
+            request_file: "wasm_rpc_request.json".to_string(),
+            response_file: "wasm_rpc_response.json".to_string(),
+            run_id: "budget".to_string(),
+            policy,
+            cassette: None,
+            started: Instant::now(),
+            calls_made: Cell::new(0),
+        };
+        assert!(config.begin_call().is_ok());
+        assert!(config.begin_call().is_ok());
+        assert!(matches!(config.begin_call(), Err(ModuleError::RpcTimeout(msg)) if msg.contains("budget exhausted")));
+    }
+
+    #[test]
+    fn test_transient_errors_are_retryable() {
+        assert!(is_retryable(&Err(ModuleError::RpcTimeout("RPC call timeout after 10000ms".to_string()))));
+        assert!(!is_retryable(&Err(ModuleError::InvalidInput("bad request".to_string()))));
+        assert!(is_retryable(&Ok(r#"{"id":1,"error":{"code":-32005,"message":"rate limited"}}"#.to_string())));
+        assert!(!is_retryable(&Ok(r#"{"id":1,"error":{"code":-32601,"message":"Method not found"}}"#.to_string())));
+        assert!(!is_retryable(&Ok(r#"{"id":1,"result":"0x1"}"#.to_string())));
+    }
+
+    #[test]
+    fn test_cassette_record_then_replay() {
+        let path = env::temp_dir().join(format!("rpc-cassette-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recorder = Cassette::open(CassetteMode::Record, path.clone()).unwrap();
+        recorder.record(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber","params":[]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#,
+        );
+        recorder.record(
+            r#"{"jsonrpc":"2.0","id":2,"method":"eth_blockNumber","params":[]}"#,
+            r#"{"jsonrpc":"2.0","id":2,"result":"0x11"}"#,
+        );
+
+        let player = Cassette::open(CassetteMode::Replay, path.clone()).unwrap();
+        let request = r#"{"jsonrpc":"2.0","id":9,"method":"eth_blockNumber","params":[]}"#;
+        let first: Value = serde_json::from_str(&player.replay(request).unwrap()).unwrap();
+        let second: Value = serde_json::from_str(&player.replay(request).unwrap()).unwrap();
+
+        assert_eq!(first, json!({"jsonrpc": "2.0", "id": 9, "result": "0x10"}));
+        assert_eq!(second["result"], json!("0x11"));
+        assert!(player.replay(request).is_err());
+        assert!(player.replay(r#"{"jsonrpc":"2.0","id":9,"method":"eth_chainId","params":[]}"#).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rpc_error_keeps_code_and_message() {
+        let error = ModuleError::from_rpc_error(&json!({"code": -32005, "message": "limit exceeded"}));
+
+        match error {
+            ModuleError::RpcError { code, message } => {
+                assert_eq!(code, -32005);
+                assert_eq!(message, "limit exceeded");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_typed_log_and_block() {
+        let log = parse_log(&json!({
+            "address": "0x00000000000000000000000000000000000000aa",
+            "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+            "data": "0x0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+            "blockNumber": "0x10",
+            "transactionHash": null,
+            "logIndex": "0x2"
+        })).unwrap();
+
+        assert_eq!(log.address, Address::from_low_u64_be(0xaa));
+        assert_eq!(log.topics.len(), 1);
+        assert_eq!(U256::from_big_endian(&log.data), U256::exp10(18));
+        assert_eq!(log.block_number, Some(16));
+        assert_eq!(log.transaction_hash, None);
+        assert_eq!(log.log_index, Some(2));
+
+        let block = parse_block(&json!({
+            "number": "0x1b4",
+            "hash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "parentHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "timestamp": "0x6553f100",
+            "gasLimit": "0x1c9c380"
+        })).unwrap();
+
+        assert_eq!(block.number, 436);
+        assert_eq!(block.timestamp, 0x6553f100);
+        assert_eq!(block.base_fee_per_gas, None);
+    }
+
+    #[test]
+    fn test_block_tag_from_input() {
+        assert_eq!(BlockTag::from_input(&json!({})).unwrap(), None);
+        assert_eq!(BlockTag::from_input(&json!({"blockNumber": 21000000})).unwrap(), Some(BlockTag::Number(21_000_000)));
+        assert_eq!(BlockTag::from_input(&json!({"blockNumber": "0x10"})).unwrap(), Some(BlockTag::Number(16)));
+        assert_eq!(BlockTag::from_input(&json!({"blockNumber": "42"})).unwrap(), Some(BlockTag::Number(42)));
+
+        let hash = "0x00000000000000000000000000000000000000000000000000000000000000ff";
+        let tag = BlockTag::from_input(&json!({"blockNumber": 1, "blockHash": hash})).unwrap().unwrap();
+        assert_eq!(tag, BlockTag::Hash(H256::from_low_u64_be(0xff)));
+        assert_eq!(tag.to_param(), json!({"blockHash": hash}));
+
+        assert!(BlockTag::from_input(&json!({"blockNumber": "latest"})).is_err());
+    }
 }
@@ -0,0 +1,87 @@
+//! Describe Action
+//!
+//! Reports the module version, the supported actions and JSON Schemas of their
+//! input and output, so workflow builders can validate `wasmInput` objects
+//! before pinning a workflow.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::action::{self, Action, ActionDescription, ACTIONS};
+use crate::common::{Envelope, ModuleInfo, MODULE, SCHEMA_VERSION};
+use crate::error::{ErrorResult, ModuleError};
+
+/// Input of the `describe` action: nothing besides `action`
+#[derive(Deserialize, JsonSchema)]
+pub struct DescribeInput {}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DescribeOutput {
+    pub ok: bool,
+    pub success: bool,
+    pub module: ModuleInfo,
+    pub schema_version: u32,
+    pub actions: Vec<ActionDescription>,
+    /// JSON Schema of the envelope printed when any action fails
+    pub error: Value,
+}
+
+pub fn describe() -> DescribeOutput {
+    DescribeOutput {
+        ok: true,
+        success: true,
+        module: MODULE,
+        schema_version: SCHEMA_VERSION,
+        actions: ACTIONS.iter().map(|a| a.describe()).collect(),
+        error: action::schema::<Envelope<ErrorResult>>(),
+    }
+}
+
+pub struct Describe;
+
+impl Action for Describe {
+    const NAME: &'static str = "describe";
+    const DESCRIPTION: &'static str = "Report module version, actions and their JSON Schemas";
+
+    type Input = DescribeInput;
+    type Output = DescribeOutput;
+
+    fn run(&self, _input: DescribeInput) -> Result<DescribeOutput, ModuleError> {
+        Ok(describe())
+    }
+}
+
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_lists_actions_with_schemas() {
+        let output = describe();
+        let names: Vec<&str> = output.actions.iter().map(|a| a.name).collect();
+        assert_eq!(names, vec!["rebalance", "describe"]);
+
+        let rebalance = &output.actions[0];
+        // RPC and legacy mode are the two accepted input shapes
+        let variants = rebalance.input["anyOf"].as_array().expect("untagged input schema");
+        assert_eq!(variants.len(), 2);
+        let rpc_fields = rebalance.input["definitions"]["RpcRebalanceInput"]["properties"].as_object().unwrap();
+        for field in ["vaultDataReader", "vault", "protocolTypes", "pools", "chainId", "config", "blockNumber", "rpc"] {
+            assert!(rpc_fields.contains_key(field), "missing {}", field);
+        }
+        assert!(rebalance.input["definitions"]["OptimizerConfig"]["properties"]["stepPct"].is_object());
+
+        let output_fields = rebalance.output["definitions"]["RebalanceOutput"]["properties"].as_object().unwrap();
+        for field in ["value", "weights", "allocations", "scenariosEvaluated", "blockNumber"] {
+            assert!(output_fields.contains_key(field), "missing {}", field);
+        }
+        assert!(rebalance.output["definitions"]["RebalanceSkip"]["properties"]["skipRemainingSteps"].is_object());
+        assert!(output.error["properties"]["inputHash"].is_object());
+    }
+}
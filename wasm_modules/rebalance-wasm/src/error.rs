@@ -0,0 +1,167 @@
+//! Module error type
+//!
+//! Every failure surfaced to the host carries a stable machine-readable `code`
+//! so workflow tooling can tell retryable RPC problems apart from
+//! deterministic input errors.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// JSON-RPC error codes worth retrying: generic server error (also used by the
+/// host bridge for upstream timeouts), internal error and rate limiting
+pub const TRANSIENT_RPC_CODES: [i64; 3] = [-32000, -32603, -32005];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModuleError {
+    /// Malformed input JSON or module configuration
+    InvalidInput(String),
+    /// A call got no response in time, or the run's RPC budget ran out
+    RpcTimeout(String),
+    /// The transport failed or returned something that is not a valid response
+    RpcTransport(String),
+    /// The node answered with a JSON-RPC error object
+    RpcError { code: i64, message: String },
+    /// A response or contract return value could not be decoded
+    AbiDecode(String),
+    /// No allocation satisfies the constraints
+    Infeasible(String),
+    /// Unexpected state inside the module
+    Internal(String),
+}
+
+/// Result payload of a failed run
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResult {
+    pub ok: bool,
+    pub success: bool,
+    /// Human-readable message
+    pub error: String,
+    /// Stable error code, e.g. `RPC_TIMEOUT`
+    pub code: String,
+    /// Whether running the same input again may succeed
+    pub retryable: bool,
+    /// JSON-RPC error code, for `RPC_ERROR` only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpc_code: Option<i64>,
+}
+
+impl ModuleError {
+    /// Build the error from a JSON-RPC `error` object
+    pub fn from_rpc_error(error: &Value) -> Self {
+        ModuleError::RpcError {
+            code: error.get("code").and_then(|v| v.as_i64()).unwrap_or(0),
+            message: error.get("message").and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| error.to_string()),
+        }
+    }
+
+    /// Stable identifier reported as `code` in the error output
+    pub fn code(&self) -> &'static str {
+        match self {
+            ModuleError::InvalidInput(_) => "INVALID_INPUT",
+            ModuleError::RpcTimeout(_) => "RPC_TIMEOUT",
+            ModuleError::RpcTransport(_) => "RPC_TRANSPORT",
+            ModuleError::RpcError { .. } => "RPC_ERROR",
+            ModuleError::AbiDecode(_) => "ABI_DECODE",
+            ModuleError::Infeasible(_) => "INFEASIBLE",
+            ModuleError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    /// Whether running the same input again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ModuleError::RpcTimeout(_) | ModuleError::RpcTransport(_) => true,
+            ModuleError::RpcError { code, .. } => TRANSIENT_RPC_CODES.contains(code),
+            _ => false,
+        }
+    }
+
+    /// Prefix the message with context, keeping the variant
+    pub fn context(self, context: &str) -> Self {
+        let wrap = |msg: String| format!("{}: {}", context, msg);
+        match self {
+            ModuleError::InvalidInput(msg) => ModuleError::InvalidInput(wrap(msg)),
+            ModuleError::RpcTimeout(msg) => ModuleError::RpcTimeout(wrap(msg)),
+            ModuleError::RpcTransport(msg) => ModuleError::RpcTransport(wrap(msg)),
+            ModuleError::RpcError { code, message } => ModuleError::RpcError { code, message: wrap(message) },
+            ModuleError::AbiDecode(msg) => ModuleError::AbiDecode(wrap(msg)),
+            ModuleError::Infeasible(msg) => ModuleError::Infeasible(wrap(msg)),
+            ModuleError::Internal(msg) => ModuleError::Internal(wrap(msg)),
+        }
+    }
+
+    /// Result payload of the module output
+    pub fn to_result(&self) -> ErrorResult {
+        ErrorResult {
+            ok: false,
+            success: false,
+            error: self.to_string(),
+            code: self.code().to_string(),
+            retryable: self.is_retryable(),
+            rpc_code: match self {
+                ModuleError::RpcError { code, .. } => Some(*code),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::RpcError { code, message } => write!(f, "{} (RPC error {})", message, code),
+            ModuleError::InvalidInput(msg)
+            | ModuleError::RpcTimeout(msg)
+            | ModuleError::RpcTransport(msg)
+            | ModuleError::AbiDecode(msg)
+            | ModuleError::Infeasible(msg)
+            | ModuleError::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_json_carries_stable_code() {
+        let error = ModuleError::from_rpc_error(&json!({"code": -32005, "message": "limit exceeded"}))
+            .context("Failed to fetch snapshot");
+
+        assert_eq!(json!(error.to_result()), json!({
+            "ok": false,
+            "success": false,
+            "error": "Failed to fetch snapshot: limit exceeded (RPC error -32005)",
+            "code": "RPC_ERROR",
+            "retryable": true,
+            "rpcCode": -32005,
+        }));
+
+        let invalid = json!(ModuleError::InvalidInput("Missing vault".to_string()).to_result());
+        assert_eq!(invalid["code"], "INVALID_INPUT");
+        assert_eq!(invalid["retryable"], false);
+        assert!(invalid.get("rpcCode").is_none());
+    }
+
+    #[test]
+    fn test_only_rpc_failures_are_retryable() {
+        assert!(ModuleError::RpcTimeout("timeout".to_string()).is_retryable());
+        assert!(ModuleError::RpcTransport("write failed".to_string()).is_retryable());
+        assert!(!ModuleError::RpcError { code: -32601, message: "Method not found".to_string() }.is_retryable());
+        assert!(!ModuleError::AbiDecode("short".to_string()).is_retryable());
+        assert!(!ModuleError::Infeasible("no allocation".to_string()).is_retryable());
+    }
+}
@@ -4,64 +4,149 @@
 //!
 //! The action is determined by the "action" field in the input JSON:
 //! - "rebalance" (default): Run yield optimizer via VaultDataReader RPC
+//! - "describe": Report module version, actions and their JSON Schemas
 //!
-//! Note: Emergency monitoring is no longer needed as the contract's
-//! updateAllGuards() auto-activates emergency mode when triggers are detected.
+//! Actions are registered in `action::ACTIONS`. The input is read from stdin
+//! by `run()`, or passed in guest memory to `run_with_input(ptr, len)`.
 
 #[macro_use]
 pub mod common;
+pub mod action;
+pub mod describe;
+pub mod error;
+pub mod multicall;
 pub mod rebalance;
 
+use error::ModuleError;
 use serde_json::Value;
-use std::io::{self, BufRead};
+use std::io::{self, Read};
 
 // Re-export memory management functions from common
 pub use common::{alloc, dealloc};
 
 // ============================================================================
-// Main Entry Point
+// Entry Points
 // ============================================================================
 
+/// WASI entry point: read the input JSON from stdin, print the output line to stdout
 #[no_mangle]
 pub extern "C" fn run() {
     log_info!("Vault Automation WASM starting");
 
-    // Read input from stdin
-    let stdin = io::stdin();
-    let input_line = stdin.lock().lines().next()
-        .unwrap_or_else(|| Ok("{}".to_string()))
-        .unwrap_or_else(|_| "{}".to_string());
+    // Read all of stdin so pretty-printed, multi-line input works too
+    let mut input_text = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut input_text) {
+        log_error!("Failed to read stdin: {}", e);
+    }
 
-    let input: Value = match serde_json::from_str(&input_line) {
-        Ok(v) => v,
+    println!("{}", handle(&input_text));
+
+    log_info!("WASM module finished");
+}
+
+/// Embedder entry point: run on the `len` bytes of input JSON at `ptr`, without WASI stdio
+///
+/// The host writes the input into a buffer from `alloc(len)` and keeps ownership
+/// of it. Returns `(out_ptr << 32) | out_len` of the output line, allocated with
+/// `alloc`; the host releases it with `dealloc(out_ptr, out_len)`. Returns 0 if
+/// the output buffer cannot be allocated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn run_with_input(ptr: *const u8, len: u32) -> u64 {
+    log_info!("Vault Automation WASM starting (memory input)");
+
+    let bytes: &[u8] = if ptr.is_null() || len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(ptr, len as usize) }
+    };
+    let output = match std::str::from_utf8(bytes) {
+        Ok(input_text) => handle(input_text),
         Err(e) => {
-            log_error!("Failed to parse input: {}", e);
-            common::output_error(&format!("Invalid input: {}", e));
-            return;
+            log_error!("Input is not valid UTF-8: {}", e);
+            let raw = Value::String(String::from_utf8_lossy(bytes).into_owned());
+            common::output::<()>("", &raw, Err(ModuleError::InvalidInput(format!("Input is not valid UTF-8: {}", e))), None)
         }
     };
 
-    // Determine action from input
-    let action = input.get("action")
-        .and_then(|v| v.as_str())
-        .unwrap_or("rebalance");
-
-    log_info!("Action: {}", action);
-
-    match action {
-        "rebalance" => {
-            // Check if this is RPC-enabled mode (has vaultDataReader field)
-            if input.get("vaultDataReader").is_some() {
-                rebalance::run_with_rpc(input);
-            } else {
-                rebalance::run_legacy(input);
-            }
+    let packed = match output_buffer(output.as_bytes()) {
+        Some((out_ptr, out_len)) => ((out_ptr as usize as u64) << 32) | out_len as u64,
+        None => {
+            log_error!("Failed to allocate {} byte output buffer", output.len());
+            0
         }
-        _ => {
-            log_error!("Unknown action: {}", action);
-            common::output_error(&format!("Unknown action: {}. Valid actions: rebalance", action));
+    };
+
+    log_info!("WASM module finished");
+    packed
+}
+
+/// Parse the input JSON, run the selected action and return the output line
+fn handle(input_text: &str) -> String {
+    // No input at all behaves like an empty object: the default action
+    let input_text = match input_text.trim() {
+        "" => "{}",
+        text => text,
+    };
+
+    let input: Value = match serde_json::from_str(input_text) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!("Failed to parse input: {}", e);
+            // Hash the raw text so the failure can still be traced to its input
+            let raw = Value::String(input_text.to_string());
+            return common::output::<()>("", &raw, Err(ModuleError::InvalidInput(format!("Invalid input: {}", e))), None);
         }
+    };
+
+    common::init_logging(&input);
+
+    action::dispatch(&input)
+}
+
+/// Copy `bytes` into a fresh `alloc` buffer owned by the host
+fn output_buffer(bytes: &[u8]) -> Option<(*mut u8, u32)> {
+    let len = u32::try_from(bytes.len()).ok()?;
+    let ptr = alloc(len);
+    if ptr.is_null() {
+        return None;
     }
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+    Some((ptr, len))
+}
 
-    log_info!("WASM module finished");
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_line_input_is_read_whole() {
+        let compact = r#"{"action":"describe","deterministic":true}"#;
+        let pretty = "{\n  \"action\": \"describe\",\n  \"deterministic\": true\n}\n";
+
+        let output: Value = serde_json::from_str(&handle(pretty)).unwrap();
+        assert_eq!(output["ok"], true);
+        assert_eq!(output["action"], "describe");
+        assert_eq!(handle(pretty), handle(compact));
+
+        let invalid: Value = serde_json::from_str(&handle("{\"action\":")).unwrap();
+        assert_eq!(invalid["result"]["code"], "INVALID_INPUT");
+    }
+
+    #[test]
+    fn test_alloc_handles_zero_and_odd_sizes() {
+        let empty = alloc(0);
+        assert!(!empty.is_null());
+        dealloc(empty, 0);
+        dealloc(std::ptr::null_mut(), 7);
+
+        let (ptr, len) = output_buffer(b"odd").unwrap();
+        assert_eq!(len, 3);
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, len as usize) }, b"odd");
+        dealloc(ptr, len);
+    }
 }
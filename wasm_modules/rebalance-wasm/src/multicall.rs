@@ -0,0 +1,183 @@
+//! Multicall3 aggregation
+//!
+//! Packs many `(target, calldata)` reads into a single `Multicall3.aggregate3`
+//! eth_call and decodes the per-call success flag and return data, so modules
+//! can read caps, `maxWithdraw`, oracle prices, etc. for every pool in one
+//! round-trip without a dedicated aggregator contract.
+
+use ethabi::{decode, encode, Function, Param, ParamType, StateMutability, Token};
+use ethereum_types::Address;
+
+use crate::common::EthClient;
+use crate::error::ModuleError;
+
+/// Multicall3 is deployed at the same address on all major chains
+pub const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+/// `aggregate3((address,bool,bytes)[])`
+const AGGREGATE3_SELECTOR: [u8; 4] = [0x82, 0xad, 0x56, 0xcb];
+
+/// A single read to aggregate
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub target: Address,
+    /// When false, a revert of this call reverts the whole aggregate
+    pub allow_failure: bool,
+    pub call_data: Vec<u8>,
+}
+
+/// Outcome of one aggregated read
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallResult {
+    pub success: bool,
+    pub return_data: Vec<u8>,
+}
+
+impl Call {
+    /// Build a call that is allowed to fail from an ABI function and its arguments
+    pub fn new(target: Address, function: &Function, args: &[Token]) -> Result<Self, ModuleError> {
+        let call_data = function.encode_input(args)
+            .map_err(|e| ModuleError::InvalidInput(format!("Failed to encode {} calldata: {}", function.name, e)))?;
+        Ok(Self { target, allow_failure: true, call_data })
+    }
+}
+
+impl CallResult {
+    /// Decode the return data with `function`'s outputs; `Err` if the call reverted
+    pub fn decode(&self, function: &Function) -> Result<Vec<Token>, ModuleError> {
+        if !self.success {
+            return Err(ModuleError::AbiDecode(format!("{} reverted", function.name)));
+        }
+        function.decode_output(&self.return_data)
+            .map_err(|e| ModuleError::AbiDecode(format!("Failed to decode {} output: {}", function.name, e)))
+    }
+}
+
+/// Describe a view function for use with [`Call::new`] and [`CallResult::decode`]
+pub fn view_function(name: &str, inputs: Vec<ParamType>, outputs: Vec<ParamType>) -> Function {
+    fn params(kinds: Vec<ParamType>) -> Vec<Param> {
+        kinds.into_iter()
+            .map(|kind| Param { name: String::new(), kind, internal_type: None })
+            .collect()
+    }
+
+    #[allow(deprecated)]
+    Function {
+        name: name.to_string(),
+        inputs: params(inputs),
+        outputs: params(outputs),
+        constant: None,
+        state_mutability: StateMutability::View,
+    }
+}
+
+/// Default Multicall3 deployment address
+pub fn default_address() -> Address {
+    MULTICALL3_ADDRESS.parse().expect("valid Multicall3 address")
+}
+
+/// Execute all `calls` through one `aggregate3` eth_call at the client's pinned block
+pub fn aggregate3(client: &EthClient, multicall: Address, calls: &[Call]) -> Result<Vec<CallResult>, ModuleError> {
+    if calls.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    log_info!("Aggregating {} calls via Multicall3", calls.len());
+
+    let call_data = encode_aggregate3(calls);
+    let result = client.eth_call(multicall, &call_data, client.block())?;
+    let results = decode_aggregate3(&result).map_err(ModuleError::AbiDecode)?;
+
+    if results.len() != calls.len() {
+        return Err(ModuleError::AbiDecode(
+            format!("Multicall3 returned {} results for {} calls", results.len(), calls.len())));
+    }
+    Ok(results)
+}
+
+fn encode_aggregate3(calls: &[Call]) -> Vec<u8> {
+    let calls_token = Token::Array(calls.iter().map(|c| Token::Tuple(vec![
+        Token::Address(c.target),
+        Token::Bool(c.allow_failure),
+        Token::Bytes(c.call_data.clone()),
+    ])).collect());
+
+    let mut data = AGGREGATE3_SELECTOR.to_vec();
+    data.extend(encode(&[calls_token]));
+    data
+}
+
+fn decode_aggregate3(data: &[u8]) -> Result<Vec<CallResult>, String> {
+    let result_type = ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])));
+
+    let tokens = decode(&[result_type], data)
+        .map_err(|e| format!("Failed to decode aggregate3 result: {}", e))?;
+
+    let items = match tokens.into_iter().next() {
+        Some(Token::Array(items)) => items,
+        _ => return Err("Invalid aggregate3 result".to_string()),
+    };
+
+    items.into_iter().map(|item| match item {
+        Token::Tuple(fields) => match fields.as_slice() {
+            [Token::Bool(success), Token::Bytes(return_data)] => Ok(CallResult {
+                success: *success,
+                return_data: return_data.clone(),
+            }),
+            _ => Err("Invalid aggregate3 result fields".to_string()),
+        },
+        _ => Err("Expected tuple in aggregate3 result".to_string()),
+    }).collect()
+}
+
+// ============================================================================
+// Unit Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_types::U256;
+
+    #[test]
+    fn test_aggregate3_selector_matches_abi() {
+        let function = view_function(
+            "aggregate3",
+            vec![ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Bool,
+                ParamType::Bytes,
+            ])))],
+            vec![],
+        );
+        assert_eq!(function.short_signature(), AGGREGATE3_SELECTOR);
+
+        let max_withdraw = view_function("maxWithdraw", vec![ParamType::Address], vec![ParamType::Uint(256)]);
+        let call = Call::new(Address::from_low_u64_be(1), &max_withdraw, &[Token::Address(Address::from_low_u64_be(2))]).unwrap();
+        let encoded = encode_aggregate3(std::slice::from_ref(&call));
+        assert_eq!(encoded, function.encode_input(&[Token::Array(vec![Token::Tuple(vec![
+            Token::Address(call.target),
+            Token::Bool(true),
+            Token::Bytes(call.call_data),
+        ])])]).unwrap());
+    }
+
+    #[test]
+    fn test_decode_aggregate3_results() {
+        let price = encode(&[Token::Uint(U256::from(123_456u64))]);
+        let data = encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(price)]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+
+        let results = decode_aggregate3(&data).unwrap();
+        assert_eq!(results.len(), 2);
+
+        let latest_answer = view_function("latestAnswer", vec![], vec![ParamType::Uint(256)]);
+        assert_eq!(results[0].decode(&latest_answer).unwrap(), vec![Token::Uint(U256::from(123_456u64))]);
+        assert!(results[1].decode(&latest_answer).is_err());
+    }
+}
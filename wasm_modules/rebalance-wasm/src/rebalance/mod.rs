@@ -10,13 +10,17 @@
 //! 4. Calculate expected 12h returns
 //! 5. Return optimal allocation
 
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use ethabi::{decode, encode, Token, ParamType, Function, Param};
-use ethereum_types::{Address, U256};
-
-use crate::common::{RpcConfig, rpc_call, PROTO_AAVE, PROTO_SPARK, PROTO_FLUID, PROTO_MORPHO, output_success, output_error};
-use crate::{log_info, log_error, log_debug};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use ethabi::{decode, Token, ParamType, Function, Param};
+use ethereum_types::{Address, U256, U512};
+
+use crate::action::Action;
+use crate::common::{SkipResult, RpcConfig, RpcPolicyOverrides, EthClient, EthRequest, BlockTag, parse_bytes, parse_block_result, PROTO_AAVE, PROTO_SPARK, PROTO_FLUID, PROTO_MORPHO};
+use crate::error::ModuleError;
 
 // ============================================================================
 // Data Structures
@@ -32,50 +36,271 @@ pub struct IRMParams {
     pub reserve_factor: f64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ProtocolState {
-    pub our_balance: f64,
-    pub pool_supply: f64,
-    pub pool_borrow: f64,
+    pub our_balance: TokenAmount,
+    pub pool_supply: TokenAmount,
+    pub pool_borrow: TokenAmount,
     pub utilization: f64,
     pub current_apy: f64,
     pub is_blocked: bool,
     pub protocol_type: u8,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct OptimizerConfig {
+    /// Grid step in percent
     #[serde(default = "default_step_pct")]
     pub step_pct: usize,
+    /// Maximum share of a pool's supply the vault may hold after the move
     #[serde(default = "default_max_pool_share")]
     pub max_pool_share: f64,
+    /// Smallest non-zero allocation, in whole tokens (e.g. 1000 = 1000 USDC)
     #[serde(default = "default_min_allocation")]
     pub min_allocation: f64,
+    /// Smallest non-zero allocation in raw token units; wins over `minAllocation`
+    #[serde(default)]
+    pub min_allocation_raw: Option<TokenAmount>,
+    /// Maximum share of the vault's assets in a single protocol
     #[serde(default = "default_max_vault_allocation_share")]
     pub max_vault_allocation_share: f64,
+    /// Most one rebalance may move, as `sum(|allocation - ourBalance|)` in
+    /// percent of the vault's assets
+    #[serde(default)]
+    pub max_turnover_pct: Option<f64>,
+    /// Most one rebalance may move, in whole tokens; the stricter limit applies
+    #[serde(default)]
+    pub max_turnover: Option<f64>,
+    /// `maxTurnover` in raw token units; wins over `maxTurnover`
+    #[serde(default)]
+    pub max_turnover_raw: Option<TokenAmount>,
+    /// Skip the rebalance unless the optimal 12h return beats the current
+    /// allocation's by at least this many bps of the current return
+    #[serde(default)]
+    pub min_improvement_bps: f64,
+    /// Skip the rebalance unless the optimal 12h return beats the current
+    /// allocation's by at least this amount, in whole tokens
+    #[serde(default)]
+    pub min_improvement_abs: f64,
+    /// `minImprovementAbs` in raw token units; wins over `minImprovementAbs`
+    #[serde(default)]
+    pub min_improvement_abs_raw: Option<TokenAmount>,
+    /// Search strategy over the `stepPct` grid
+    #[serde(default)]
+    pub solver: Solver,
+    /// Cost of moving funds in or out of each protocol, by protocol index;
+    /// protocols without an entry move for free
+    #[serde(default)]
+    pub protocol_costs: Vec<ProtocolCost>,
+    /// Gas cost of `executeRebalance`; not charged if absent
+    #[serde(default)]
+    pub gas: Option<GasConfig>,
+}
+
+/// Cost of changing one protocol's allocation, subtracted from the 12h return
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolCost {
+    /// Charged whenever the allocation changes, in whole tokens (adapter fees, ERC-4626 rounding)
+    #[serde(default)]
+    pub fixed: f64,
+    /// Charged on the amount moved in or out, in bps
+    #[serde(default)]
+    pub proportional_bps: f64,
+}
+
+/// How to price the gas of `executeRebalance` in the vault asset
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GasConfig {
+    /// Price of 1 ETH in whole tokens of the vault asset (1 for a WETH vault)
+    pub asset_per_eth: f64,
+    /// Gas used by `executeRebalance`; estimated with `eth_estimateGas` in RPC mode if absent
+    #[serde(default)]
+    pub gas_limit: Option<u64>,
+    /// Sender the gas estimate simulates, i.e. the account allowed to rebalance
+    #[serde(default)]
+    pub from: Option<String>,
+    /// Gas price in wei; in RPC mode defaults to the pinned block's base fee plus
+    /// `priorityFeeWei`, or `eth_gasPrice` on chains without a base fee
+    #[serde(default)]
+    pub gas_price_wei: Option<TokenAmount>,
+    /// Tip added to the base fee, in wei
+    #[serde(default)]
+    pub priority_fee_wei: Option<TokenAmount>,
+}
+
+/// How `optimize` searches the `stepPct` grid
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Solver {
+    /// Evaluate every weight combination
+    #[default]
+    Grid,
+    /// Water-filling: hand out one step at a time to the protocol whose 12h
+    /// return rises most. Matches the grid when each protocol's return is
+    /// concave in its deposit, in milliseconds instead of seconds.
+    Greedy,
 }
 
 fn default_step_pct() -> usize { 1 }
 fn default_max_pool_share() -> f64 { 0.2 }
 fn default_min_allocation() -> f64 { 1000.0 }
 fn default_max_vault_allocation_share() -> f64 { 0.4 }
+fn default_chain_id() -> u64 { 1 }
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        Self {
+            step_pct: default_step_pct(),
+            max_pool_share: default_max_pool_share(),
+            min_allocation: default_min_allocation(),
+            min_allocation_raw: None,
+            max_vault_allocation_share: default_max_vault_allocation_share(),
+            max_turnover_pct: None,
+            max_turnover: None,
+            max_turnover_raw: None,
+            min_improvement_bps: 0.0,
+            min_improvement_abs: 0.0,
+            min_improvement_abs_raw: None,
+            solver: Solver::Grid,
+            protocol_costs: Vec::new(),
+            gas: None,
+        }
+    }
+}
+
+impl OptimizerConfig {
+    /// `minAllocation` in raw units of an asset with `decimals` decimals
+    pub fn min_allocation_units(&self, decimals: u8) -> f64 {
+        self.min_allocation_raw
+            .map(|raw| raw.as_f64())
+            .unwrap_or_else(|| to_raw_units(self.min_allocation, decimals))
+    }
+
+    /// Turnover limit in raw units of an asset with `decimals` decimals; infinite without one
+    pub fn max_turnover_units(&self, total_assets: f64, decimals: u8) -> f64 {
+        let relative = self.max_turnover_pct.map_or(f64::INFINITY, |pct| total_assets * pct / 100.0);
+        let absolute = self.max_turnover_raw.map(|raw| raw.as_f64())
+            .or_else(|| self.max_turnover.map(|whole| to_raw_units(whole, decimals)))
+            .unwrap_or(f64::INFINITY);
+        relative.min(absolute)
+    }
+
+    /// `minImprovementAbs` in raw units of an asset with `decimals` decimals
+    pub fn min_improvement_abs_units(&self, decimals: u8) -> f64 {
+        self.min_improvement_abs_raw
+            .map(|raw| raw.as_f64())
+            .unwrap_or_else(|| to_raw_units(self.min_improvement_abs, decimals))
+    }
+}
 
-#[derive(Debug, Clone, Deserialize)]
+/// Legacy-mode input: protocol state passed in directly
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct OptimizerInput {
-    pub total_assets: f64,
+    pub total_assets: TokenAmount,
     pub protocols: Vec<ProtocolState>,
     #[serde(default)]
     pub blocked_mask: u8,
     #[serde(default)]
     pub config: Option<OptimizerConfig>,
+    /// Vault the `executeRebalance` transaction is addressed to, if known
+    #[serde(default)]
+    pub vault: Option<String>,
+    /// Decimals of the vault asset; 0 (human units = raw units) if absent
+    #[serde(default)]
+    pub asset_decimals: Option<u8>,
+    /// Print byte-identical output for consensus hashing; defaults to WASM_DETERMINISTIC
+    #[serde(default)]
+    pub deterministic: Option<bool>,
+}
+
+/// RPC-mode input: protocol state is read through VaultDataReader
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcRebalanceInput {
+    /// VaultDataReader contract address
+    pub vault_data_reader: String,
+    pub vault: String,
+    /// Protocol type of each pool: 1 Aave V3, 2 Spark, 3 Fluid V2, 4 MetaMorpho
+    #[serde(default)]
+    pub protocol_types: Vec<u8>,
+    #[serde(default)]
+    pub pools: Vec<String>,
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u64,
+    #[serde(default)]
+    pub config: Option<OptimizerConfig>,
+    /// Block to pin all reads to: a number, decimal string or 0x-prefixed hex string
+    #[serde(default)]
+    #[schemars(with = "Option<BlockNumberSchema>")]
+    pub block_number: Option<Value>,
+    /// Block hash to pin all reads to; wins over `blockNumber`
+    #[serde(default)]
+    pub block_hash: Option<String>,
+    #[serde(default)]
+    pub rpc: Option<RpcPolicyOverrides>,
+    /// Log filter overriding WASM_LOG, e.g. `debug`
+    #[serde(default)]
+    pub log: Option<String>,
+    /// Print byte-identical output for consensus hashing; defaults to WASM_DETERMINISTIC
+    #[serde(default)]
+    pub deterministic: Option<bool>,
+}
+
+impl RpcRebalanceInput {
+    /// Block requested by `blockHash` / `blockNumber`, if any
+    pub fn block(&self) -> Result<Option<BlockTag>, ModuleError> {
+        BlockTag::parse(self.block_number.as_ref(), self.block_hash.as_deref())
+    }
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum BlockNumberSchema {
+    Number(u64),
+    Text(String),
+}
+
+/// Input of the `rebalance` action; RPC mode is selected by `vaultDataReader`
+#[derive(Debug, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum RebalanceInput {
+    Rpc(Box<RpcRebalanceInput>),
+    Legacy(Box<OptimizerInput>),
+}
+
+impl RebalanceInput {
+    pub fn config(&self) -> Option<&OptimizerConfig> {
+        match self {
+            RebalanceInput::Rpc(params) => params.config.as_ref(),
+            RebalanceInput::Legacy(input) => input.config.as_ref(),
+        }
+    }
+}
+
+// Not `#[serde(untagged)]`: picking the variant up front keeps the field-level
+// error of the intended mode instead of "did not match any variant"
+impl<'de> Deserialize<'de> for RebalanceInput {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let input = if value.get("vaultDataReader").is_some() {
+            serde_json::from_value(value).map(|params| RebalanceInput::Rpc(Box::new(params)))
+        } else {
+            serde_json::from_value(value).map(|input| RebalanceInput::Legacy(Box::new(input)))
+        };
+        input.map_err(serde::de::Error::custom)
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct OptimizationResult {
+    pub total_assets: TokenAmount,
     pub allocations: Vec<String>,        // As hex strings for contract (actual amounts)
     pub allocations_decimal: Vec<f64>,   // As decimal for debugging
     pub weights: Vec<String>,            // As hex strings in WAD format (1e18 scale) for executeRebalance
@@ -83,8 +308,323 @@ pub struct OptimizationResult {
     pub expected_return_12h: f64,
     pub expected_apy_weighted: f64,
     pub apys: Vec<f64>,
+    /// Complete grid combinations whose projected return was computed and
+    /// compared; the greedy solver builds a single one
     pub scenarios_evaluated: usize,
-    pub time_ms: f64,
+    /// Search effort: grid combinations visited on the full grid, tree nodes
+    /// walked by branch and bound, or marginal returns tried by the greedy solver
+    pub nodes_visited: usize,
+    /// Projected 12h return of staying put; null if the current allocation breaks a constraint
+    pub current_return_12h: Option<f64>,
+    /// `protocolCosts` of moving to `allocations`
+    pub movement_cost: f64,
+    /// Gas of `executeRebalance`; null without `config.gas`
+    pub gas: Option<GasCost>,
+    /// `expectedReturn12h` minus the movement and gas costs; what the move is judged on
+    pub net_return_12h: f64,
+}
+
+impl OptimizationResult {
+    /// Charge the gas of executing the rebalance against the net return
+    pub fn with_gas(mut self, gas: Option<GasCost>) -> Self {
+        self.net_return_12h = self.expected_return_12h - self.movement_cost - gas.as_ref().map_or(0.0, |g| g.cost);
+        self.gas = gas;
+        self
+    }
+
+    /// Movement and gas costs together
+    pub fn total_cost(&self) -> f64 {
+        self.movement_cost + self.gas.as_ref().map_or(0.0, |g| g.cost)
+    }
+}
+
+/// Gas cost of `executeRebalance`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GasCost {
+    pub gas_units: u64,
+    pub gas_price_wei: TokenAmount,
+    /// `gasUnits * gasPriceWei` converted with `assetPerEth`, in raw asset units
+    pub cost: f64,
+}
+
+/// Result payload of the `rebalance` action, identical in RPC and legacy mode
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RebalanceOutput {
+    pub ok: bool,
+    pub success: bool,
+    /// What `$wasm:<id>` references resolve to: the WAD weights for `executeRebalance`
+    pub value: Vec<String>,
+    #[serde(flatten)]
+    pub optimization: OptimizationResult,
+    /// `executeRebalance` call carrying `weights`, for inspection or manual submission
+    pub transaction: RebalanceTransaction,
+    /// Decimals of the vault asset: `decimals()` in RPC mode, `assetDecimals` in legacy mode
+    pub asset_decimals: u8,
+    /// Amounts in whole tokens; all other amounts are raw token units
+    pub human: HumanAmounts,
+    /// Block the RPC reads were pinned to; null in legacy mode
+    pub block_number: Option<u64>,
+    pub block_hash: Option<String>,
+}
+
+/// Rebalance amounts in whole tokens
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HumanAmounts {
+    /// Exact decimal string
+    pub total_assets: String,
+    /// Exact decimal strings
+    pub allocations: Vec<String>,
+    pub expected_return_12h: f64,
+    pub current_return_12h: Option<f64>,
+    pub net_return_12h: f64,
+}
+
+/// Ready-to-send `executeRebalance(uint256[])` transaction
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RebalanceTransaction {
+    /// Vault address; null in legacy mode without `vault`
+    pub to: Option<String>,
+    pub signature: String,
+    /// 4-byte function selector, 0x-prefixed
+    pub selector: String,
+    /// ABI-encoded calldata including the selector, 0x-prefixed
+    pub data: String,
+}
+
+impl RebalanceOutput {
+    pub fn new(
+        optimization: OptimizationResult,
+        block: Option<BlockTag>,
+        vault: Option<Address>,
+        asset_decimals: u8,
+    ) -> Result<Self, ModuleError> {
+        let transaction = vault_tx::execute_rebalance(vault, &optimization.weights)?;
+        let human = HumanAmounts {
+            total_assets: format_units(optimization.total_assets.0, asset_decimals),
+            allocations: optimization.allocations.iter()
+                .map(|a| TokenAmount::parse(&Value::String(a.clone()))
+                    .map(|units| format_units(units.0, asset_decimals))
+                    .map_err(ModuleError::Internal))
+                .collect::<Result<_, _>>()?,
+            expected_return_12h: from_raw_units(optimization.expected_return_12h, asset_decimals),
+            current_return_12h: optimization.current_return_12h.map(|r| from_raw_units(r, asset_decimals)),
+            net_return_12h: from_raw_units(optimization.net_return_12h, asset_decimals),
+        };
+        Ok(Self {
+            ok: true,
+            success: true,
+            value: optimization.weights.clone(),
+            optimization,
+            transaction,
+            asset_decimals,
+            human,
+            block_number: block.and_then(|b| b.number()),
+            block_hash: block.and_then(|b| b.hash()).map(|h| format!("{:?}", h)),
+        })
+    }
+
+    /// Skip instead when the optimum does not beat staying put, or beats it by
+    /// less than the configured `minImprovementBps` / `minImprovementAbs`
+    pub fn or_skip(self, config: &OptimizerConfig) -> RebalanceResult {
+        let min_improvement_abs = config.min_improvement_abs_units(self.asset_decimals);
+        let cost = self.optimization.total_cost();
+        // A current allocation that breaks a constraint has to move regardless of return
+        let Some(current_return) = self.optimization.current_return_12h else {
+            return RebalanceResult::Rebalance(Box::new(self));
+        };
+
+        // Staying put costs nothing, so the move is judged net of its costs and
+        // only made when it returns more than the current allocation
+        let optimal_return = self.optimization.net_return_12h;
+        let improvement = optimal_return - current_return;
+        let required = min_improvement_abs
+            .max(current_return.abs() * config.min_improvement_bps / 10_000.0);
+        if improvement > 0.0 && improvement >= required {
+            return RebalanceResult::Rebalance(Box::new(self));
+        }
+
+        log_info!("Improvement below threshold, skipping rebalance";
+            improvement_12h = improvement, required_improvement_12h = required);
+
+        let decimals = self.asset_decimals;
+        let message = format!(
+            "Optimal 12h return {} net of {} costs improves on current {} by {}; rebalancing needs more than 0 and at least {} (whole tokens)",
+            from_raw_units(optimal_return, decimals), from_raw_units(cost, decimals), from_raw_units(current_return, decimals),
+            from_raw_units(improvement, decimals), from_raw_units(required, decimals));
+        RebalanceResult::Skip(RebalanceSkip {
+            skip: SkipResult::new(&message),
+            optimal_return_12h: optimal_return,
+            current_return_12h: current_return,
+            required_improvement_12h: required,
+            cost,
+            asset_decimals: decimals,
+            human: HumanReturns {
+                optimal_return_12h: from_raw_units(optimal_return, decimals),
+                cost: from_raw_units(cost, decimals),
+                current_return_12h: from_raw_units(current_return, decimals),
+                required_improvement_12h: from_raw_units(required, decimals),
+            },
+            block_number: self.block_number,
+            block_hash: self.block_hash,
+        })
+    }
+}
+
+/// Result payload of a `rebalance` run that is not worth executing
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RebalanceSkip {
+    /// `skipRemainingSteps: true`, with the reason as `message`
+    #[serde(flatten)]
+    pub skip: SkipResult,
+    pub optimal_return_12h: f64,
+    pub current_return_12h: f64,
+    /// Smallest improvement over the current return that would have rebalanced
+    pub required_improvement_12h: f64,
+    /// Movement and gas costs already subtracted from `optimalReturn12h`
+    pub cost: f64,
+    pub asset_decimals: u8,
+    /// The returns above in whole tokens
+    pub human: HumanReturns,
+    pub block_number: Option<u64>,
+    pub block_hash: Option<String>,
+}
+
+/// Skip returns in whole tokens
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HumanReturns {
+    pub optimal_return_12h: f64,
+    pub cost: f64,
+    pub current_return_12h: f64,
+    pub required_improvement_12h: f64,
+}
+
+/// Result payload of the `rebalance` action: new weights, or a skip
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RebalanceResult {
+    Rebalance(Box<RebalanceOutput>),
+    Skip(RebalanceSkip),
+}
+
+// ============================================================================
+// Token Amounts
+// ============================================================================
+
+/// An amount in integer token units, kept exact as a `U256`
+///
+/// Deserializes from a non-negative integer JSON number (floats only when they
+/// are integers below 2^53), a decimal string or a 0x-prefixed hex string, and
+/// serializes as a decimal string. Only rate math converts it to `f64`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TokenAmount(pub U256);
+
+/// Largest integer every smaller integer of which is exact in an `f64`
+const MAX_EXACT_F64: f64 = 9_007_199_254_740_992.0;
+
+impl TokenAmount {
+    pub fn as_f64(&self) -> f64 {
+        u256_to_f64(self.0)
+    }
+
+    pub fn parse(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::Number(n) => {
+                if let Some(units) = n.as_u64() {
+                    return Ok(TokenAmount(U256::from(units)));
+                }
+                match n.as_f64() {
+                    Some(f) if f >= 0.0 && f.fract() == 0.0 && f <= MAX_EXACT_F64 => Ok(TokenAmount(U256::from(f as u64))),
+                    Some(f) if f > MAX_EXACT_F64 => Err(format!(
+                        "amount {} is too large for an exact JSON number; pass it as a decimal string", n)),
+                    _ => Err(format!("amount {} is not a non-negative integer", n)),
+                }
+            }
+            Value::String(s) => {
+                let parsed = match s.strip_prefix("0x") {
+                    Some(hex) => U256::from_str_radix(hex, 16).map_err(|e| format!("{:?}", e)),
+                    None => U256::from_dec_str(s).map_err(|e| format!("{:?}", e)),
+                };
+                parsed.map(TokenAmount)
+                    .map_err(|e| format!("invalid amount {:?} ({}); amounts must be integers below 2^256", s, e))
+            }
+            other => Err(format!("invalid amount {}; expected an integer or a string", other)),
+        }
+    }
+}
+
+impl From<U256> for TokenAmount {
+    fn from(units: U256) -> Self {
+        TokenAmount(units)
+    }
+}
+
+impl Serialize for TokenAmount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for TokenAmount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        TokenAmount::parse(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum TokenAmountSchema {
+    Integer(u64),
+    /// Decimal or 0x-prefixed hex
+    Text(String),
+}
+
+impl JsonSchema for TokenAmount {
+    fn schema_name() -> String {
+        "TokenAmount".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        TokenAmountSchema::json_schema(gen)
+    }
+}
+
+/// Largest supported asset decimals: 10^77 is the largest power of ten in a `U256`
+pub const MAX_DECIMALS: u8 = 77;
+
+/// Exact decimal string of `units` raw units of an asset with `decimals` decimals
+pub fn format_units(units: U256, decimals: u8) -> String {
+    let digits = units.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - decimals);
+    match fraction.trim_end_matches('0') {
+        "" => whole.to_string(),
+        fraction => format!("{}.{}", whole, fraction),
+    }
+}
+
+fn to_raw_units(whole_tokens: f64, decimals: u8) -> f64 {
+    whole_tokens * 10f64.powi(decimals as i32)
+}
+
+fn from_raw_units(units: f64, decimals: u8) -> f64 {
+    units / 10f64.powi(decimals as i32)
+}
+
+/// Nearest `f64`, for rate math only; never truncates high bits
+fn u256_to_f64(value: U256) -> f64 {
+    const LIMB: f64 = 18_446_744_073_709_551_616.0; // 2^64
+    value.0.iter().rev().fold(0.0, |acc, &limb| acc * LIMB + limb as f64)
 }
 
 // ============================================================================
@@ -95,6 +635,7 @@ mod vault_reader {
     use super::*;
 
     #[derive(Debug)]
+    #[allow(dead_code)]
     pub struct VaultSnapshot {
         pub asset: Address,
         pub total_assets: U256,
@@ -108,6 +649,7 @@ mod vault_reader {
     }
 
     #[derive(Debug)]
+    #[allow(dead_code)]
     pub struct ProtocolData {
         pub protocol_type: u8,
         pub pool: Address,
@@ -134,6 +676,7 @@ mod vault_reader {
     }
 
     #[derive(Debug)]
+    #[allow(dead_code)]
     pub struct GuardState {
         pub blocked_mask: u8,
         pub emergency_mode: bool,
@@ -142,51 +685,63 @@ mod vault_reader {
 
     /// Call VaultDataReader.getSnapshot() via eth_call
     pub fn get_snapshot(
-        rpc_config: &RpcConfig,
+        client: &EthClient,
         vault_data_reader: &str,
         vault: &str,
         protocol_types: &[u8],
         pools: &[String],
-        chain_id: u64,
-    ) -> Result<VaultSnapshot, String> {
+    ) -> Result<VaultSnapshot, ModuleError> {
         log_info!("Fetching vault snapshot via VaultDataReader");
 
+        let reader_addr: Address = vault_data_reader.parse()
+            .map_err(|e| ModuleError::InvalidInput(format!("Invalid vaultDataReader address: {}", e)))?;
         let call_data = encode_get_snapshot_call(vault, protocol_types, pools)?;
-        log_debug!("getSnapshot calldata: {}", call_data);
-
-        let request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": 1,
-            "method": "eth_call",
-            "chainId": chain_id,
-            "params": [{
-                "to": vault_data_reader,
-                "data": call_data
-            }, "latest"]
-        });
+        log_debug!("getSnapshot calldata: 0x{}", hex::encode(&call_data));
+
+        let result = client.eth_call(reader_addr, &call_data, client.block())?;
 
-        let response = rpc_call(rpc_config, &request)?;
+        decode_vault_snapshot(&result).map_err(ModuleError::AbiDecode)
+    }
+
+    fn decimals_function() -> Function {
+        crate::multicall::view_function("decimals", vec![], vec![ParamType::Uint(8)])
+    }
 
-        let result_hex = response.get("result")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| "No result in getSnapshot response".to_string())?;
+    /// ERC-20 `decimals()` call of the vault asset at the pinned block
+    pub fn asset_decimals_request(client: &EthClient, asset: Address) -> Result<EthRequest, ModuleError> {
+        let call_data = decimals_function().encode_input(&[])
+            .map_err(|e| ModuleError::Internal(format!("Failed to encode decimals calldata: {}", e)))?;
+        Ok(EthRequest::call(asset, &call_data, client.block()))
+    }
 
-        decode_vault_snapshot(result_hex)
+    /// Decode the `decimals()` return value
+    pub fn decode_asset_decimals(result: &[u8]) -> Result<u8, ModuleError> {
+        let decimals = match decimals_function().decode_output(result)
+            .map_err(|e| ModuleError::AbiDecode(format!("Failed to decode decimals: {}", e)))?
+            .first()
+        {
+            Some(Token::Uint(d)) => *d,
+            _ => return Err(ModuleError::AbiDecode("Invalid decimals output".to_string())),
+        };
+        if decimals > U256::from(MAX_DECIMALS) {
+            return Err(ModuleError::AbiDecode(format!("Unsupported asset decimals: {}", decimals)));
+        }
+        Ok(decimals.low_u32() as u8)
     }
 
-    fn encode_get_snapshot_call(
+    pub fn encode_get_snapshot_call(
         vault: &str,
         protocol_types: &[u8],
         pools: &[String],
-    ) -> Result<String, String> {
+    ) -> Result<Vec<u8>, ModuleError> {
         let vault_addr: Address = vault.parse()
-            .map_err(|e| format!("Invalid vault address: {}", e))?;
+            .map_err(|e| ModuleError::InvalidInput(format!("Invalid vault address: {}", e)))?;
 
         let pool_addrs: Result<Vec<Address>, _> = pools.iter()
             .map(|p| p.parse())
             .collect();
         let pool_addrs = pool_addrs
-            .map_err(|e| format!("Invalid pool address: {}", e))?;
+            .map_err(|e| ModuleError::InvalidInput(format!("Invalid pool address: {}", e)))?;
 
         let vault_token = Token::Address(vault_addr);
         let types_token = Token::Array(
@@ -196,6 +751,7 @@ mod vault_reader {
             pool_addrs.iter().map(|&a| Token::Address(a)).collect()
         );
 
+        #[allow(deprecated)]
         let function = Function {
             name: "getSnapshot".to_string(),
             inputs: vec![
@@ -208,17 +764,11 @@ mod vault_reader {
             state_mutability: ethabi::StateMutability::View,
         };
 
-        let encoded = function.encode_input(&[vault_token, types_token, pools_token])
-            .map_err(|e| format!("Failed to encode calldata: {}", e))?;
-
-        Ok(format!("0x{}", hex::encode(encoded)))
+        function.encode_input(&[vault_token, types_token, pools_token])
+            .map_err(|e| ModuleError::InvalidInput(format!("Failed to encode calldata: {}", e)))
     }
 
-    fn decode_vault_snapshot(hex_str: &str) -> Result<VaultSnapshot, String> {
-        let hex_clean = hex_str.strip_prefix("0x").unwrap_or(hex_str);
-        let all_bytes = hex::decode(hex_clean)
-            .map_err(|e| format!("Failed to decode hex: {}", e))?;
-
+    pub fn decode_vault_snapshot(all_bytes: &[u8]) -> Result<VaultSnapshot, String> {
         // Skip the first 32-byte offset pointer (Solidity returns struct with dynamic fields wrapped in offset)
         if all_bytes.len() < 32 {
             return Err(format!("Response too short: {} bytes", all_bytes.len()));
@@ -263,7 +813,7 @@ mod vault_reader {
             ]),
         ];
 
-        let tokens = decode(&param_types, &bytes)
+        let tokens = decode(&param_types, bytes)
             .map_err(|e| format!("Failed to decode ABI: {}", e))?;
 
         parse_snapshot_tokens(&tokens)
@@ -316,7 +866,7 @@ mod vault_reader {
 
         let protocols = match &tokens[7] {
             Token::Array(arr) => {
-                arr.iter().map(|t| parse_protocol_token(t)).collect::<Result<Vec<_>, _>>()?
+                arr.iter().map(parse_protocol_token).collect::<Result<Vec<_>, _>>()?
             }
             _ => return Err("Invalid protocols token".to_string()),
         };
@@ -480,6 +1030,52 @@ mod vault_reader {
     }
 }
 
+// ============================================================================
+// executeRebalance Calldata
+// ============================================================================
+
+mod vault_tx {
+    use super::*;
+    use ethabi::{StateMutability, Token};
+
+    pub fn execute_rebalance_function() -> Function {
+        #[allow(deprecated)]
+        Function {
+            name: "executeRebalance".to_string(),
+            inputs: vec![
+                Param { name: "weights".to_string(), kind: ParamType::Array(Box::new(ParamType::Uint(256))), internal_type: None },
+            ],
+            outputs: vec![],
+            constant: None,
+            state_mutability: StateMutability::NonPayable,
+        }
+    }
+
+    /// ABI-encoded `executeRebalance(weights)` from the result's WAD hex weights
+    pub fn execute_rebalance_calldata(weights: &[String]) -> Result<Vec<u8>, ModuleError> {
+        let weights: Vec<U256> = weights.iter()
+            .map(|w| U256::from_str_radix(w.strip_prefix("0x").unwrap_or(w), 16)
+                .map_err(|e| ModuleError::Internal(format!("Invalid WAD weight {}: {}", w, e))))
+            .collect::<Result<_, _>>()?;
+
+        execute_rebalance_function().encode_input(&[Token::Array(weights.into_iter().map(Token::Uint).collect())])
+            .map_err(|e| ModuleError::Internal(format!("Failed to encode executeRebalance calldata: {}", e)))
+    }
+
+    /// Encode `executeRebalance(weights)` from the result's WAD hex weights
+    pub fn execute_rebalance(vault: Option<Address>, weights: &[String]) -> Result<RebalanceTransaction, ModuleError> {
+        let function = execute_rebalance_function();
+        let call_data = execute_rebalance_calldata(weights)?;
+
+        Ok(RebalanceTransaction {
+            to: vault.map(|v| format!("{:?}", v)),
+            signature: function.signature().trim_end_matches(':').to_string(),
+            selector: format!("0x{}", hex::encode(function.short_signature())),
+            data: format!("0x{}", hex::encode(&call_data)),
+        })
+    }
+}
+
 // ============================================================================
 // IRM (Interest Rate Model) Functions
 // ============================================================================
@@ -548,10 +1144,7 @@ fn calc_dilution_time_delta(last_update: u64, snapshot_timestamp: u64) -> u64 {
     if snapshot_timestamp == 0 { return FALLBACK; }
     if last_update == 0 || last_update >= snapshot_timestamp { return FALLBACK; }
 
-    let mut time_delta = snapshot_timestamp - last_update;
-    if time_delta < MIN_DELTA { time_delta = MIN_DELTA; }
-    if time_delta > MAX_DELTA { time_delta = MAX_DELTA; }
-    time_delta
+    (snapshot_timestamp - last_update).clamp(MIN_DELTA, MAX_DELTA)
 }
 
 fn calc_dilution_current_apy(
@@ -594,10 +1187,10 @@ fn get_default_irm_params(protocol_type: u8) -> (f64, f64, f64, f64, f64, f64) {
 
 fn calc_supply_apy_with_irm(protocol: &ProtocolState, delta: f64, irm: &IRMParams) -> f64 {
     if protocol.protocol_type == PROTO_MORPHO {
-        return calc_metamorpho_apy_after_delta(protocol.current_apy, protocol.pool_supply, delta);
+        return calc_metamorpho_apy_after_delta(protocol.current_apy, protocol.pool_supply.as_f64(), delta);
     }
 
-    let new_util = calc_new_utilization(protocol.pool_supply, protocol.pool_borrow, delta);
+    let new_util = calc_new_utilization(protocol.pool_supply.as_f64(), protocol.pool_borrow.as_f64(), delta);
     let borrow_rate = if irm.kink2 > 0.0 && irm.kink2 > irm.kink1 {
         calc_borrow_rate_double_kink(new_util, irm.kink1, irm.rate_at_kink1, irm.kink2, irm.rate_at_kink2, irm.rate_at_max)
     } else {
@@ -609,10 +1202,10 @@ fn calc_supply_apy_with_irm(protocol: &ProtocolState, delta: f64, irm: &IRMParam
 
 fn calc_supply_apy(protocol: &ProtocolState, delta: f64) -> f64 {
     if protocol.protocol_type == PROTO_MORPHO {
-        return calc_metamorpho_apy_after_delta(protocol.current_apy, protocol.pool_supply, delta);
+        return calc_metamorpho_apy_after_delta(protocol.current_apy, protocol.pool_supply.as_f64(), delta);
     }
 
-    let new_util = calc_new_utilization(protocol.pool_supply, protocol.pool_borrow, delta);
+    let new_util = calc_new_utilization(protocol.pool_supply.as_f64(), protocol.pool_borrow.as_f64(), delta);
     let (kink1, rate_kink1, kink2, rate_kink2, rate_max, reserve_factor) = get_default_irm_params(protocol.protocol_type);
 
     let borrow_rate = if kink2 > 0.0 && kink2 > kink1 {
@@ -628,71 +1221,52 @@ fn calc_supply_apy(protocol: &ProtocolState, delta: f64) -> f64 {
 // Grid Generation
 // ============================================================================
 
-fn generate_weight_grid(n_protocols: usize, step_pct: usize) -> Vec<Vec<f64>> {
+/// Call `visit` with every weight combination of the full grid, in order
+///
+/// Combinations are built in place in one buffer, so memory stays bounded by
+/// the number of protocols however large the grid is. Returns the number of
+/// combinations visited.
+fn visit_weight_grid(n_protocols: usize, step_pct: usize, mut visit: impl FnMut(&[f64])) -> usize {
     let n_steps = 100 / step_pct;
     let step = 1.0 / n_steps as f64;
-    let mut weights = Vec::new();
 
-    fn generate_recursive(
+    fn visit_recursive(
         depth: usize, n_protocols: usize, remaining: usize, step: f64,
-        current: &mut Vec<f64>, weights: &mut Vec<Vec<f64>>,
+        current: &mut Vec<f64>, visit: &mut dyn FnMut(&[f64]), visited: &mut usize,
     ) {
         if depth == n_protocols - 1 {
             current.push(remaining as f64 * step);
-            weights.push(current.clone());
+            visit(current);
+            *visited += 1;
             current.pop();
             return;
         }
 
         for w in 0..=remaining {
             current.push(w as f64 * step);
-            generate_recursive(depth + 1, n_protocols, remaining - w, step, current, weights);
+            visit_recursive(depth + 1, n_protocols, remaining - w, step, current, visit, visited);
             current.pop();
         }
     }
 
-    let mut current = Vec::new();
-    generate_recursive(0, n_protocols, n_steps, step, &mut current, &mut weights);
-    weights
-}
-
-fn generate_bounded_weight_grid(n_protocols: usize, step_pct: usize, max_weights_pct: &[usize]) -> Vec<Vec<f64>> {
-    let max_steps: Vec<usize> = max_weights_pct.iter()
-        .map(|w| (*w / step_pct).min(100 / step_pct))
-        .collect();
-    let total_steps = 100 / step_pct;
-    let mut results = Vec::new();
-
-    fn generate_recursive(
-        depth: usize, n_protocols: usize, remaining: usize, step_pct: usize,
-        max_steps: &[usize], current: &mut Vec<f64>, results: &mut Vec<Vec<f64>>,
-    ) {
-        if depth == n_protocols - 1 {
-            if remaining <= max_steps[depth] {
-                current.push(remaining as f64 * step_pct as f64 / 100.0);
-                results.push(current.clone());
-                current.pop();
-            }
-            return;
-        }
-
-        let max_for_this = max_steps[depth].min(remaining);
-        for w in 0..=max_for_this {
-            current.push(w as f64 * step_pct as f64 / 100.0);
-            generate_recursive(depth + 1, n_protocols, remaining - w, step_pct, max_steps, current, results);
-            current.pop();
-        }
+    let mut visited = 0;
+    if n_protocols > 0 {
+        let mut current = Vec::with_capacity(n_protocols);
+        visit_recursive(0, n_protocols, n_steps, step, &mut current, &mut visit, &mut visited);
     }
+    visited
+}
 
-    let mut current = Vec::new();
-    generate_recursive(0, n_protocols, total_steps, step_pct, &max_steps, &mut current, &mut results);
-    results
+/// Weight of `steps` steps of `step_pct` percent on the bounded grid
+fn grid_weight(steps: usize, step_pct: usize) -> f64 {
+    steps as f64 * step_pct as f64 / 100.0
 }
 
 // ============================================================================
 // Constraint Filtering
 // ============================================================================
 
+#[allow(clippy::too_many_arguments)]
 fn is_valid_allocation(
     allocations: &[f64],
     protocols: &[ProtocolState],
@@ -701,176 +1275,200 @@ fn is_valid_allocation(
     min_allocation: f64,
     max_vault_allocation_share: f64,
     total_assets: f64,
+    max_turnover: f64,
 ) -> bool {
-    for (i, &alloc) in allocations.iter().enumerate() {
-        let protocol = &protocols[i];
-
+    let per_protocol = allocations.iter().zip(protocols.iter()).enumerate().all(|(i, (&alloc, protocol))| {
         let is_blocked = (blocked_mask & (1 << i)) != 0;
-        if is_blocked && alloc > protocol.our_balance {
-            return false;
-        }
+        is_valid_protocol_allocation(alloc, protocol, is_blocked, max_pool_share, min_allocation, max_vault_allocation_share, total_assets)
+    });
 
-        let delta = alloc - protocol.our_balance;
-        let new_pool_supply = protocol.pool_supply + delta;
-        if new_pool_supply > 0.0 && alloc > new_pool_supply * max_pool_share {
-            return false;
-        }
+    // Turnover constraint: the total moved in one rebalance is capped
+    per_protocol && turnover(allocations, protocols) <= max_turnover
+}
 
-        if alloc > 0.0 && alloc < min_allocation {
-            return false;
-        }
+/// Total moved by going from the current balances to `allocations`
+fn turnover(allocations: &[f64], protocols: &[ProtocolState]) -> f64 {
+    allocations.iter().zip(protocols.iter())
+        .map(|(&alloc, protocol)| moved(alloc, protocol))
+        .sum()
+}
 
-        // Max vault allocation share constraint: no single protocol can have more than X% of vault
-        if total_assets > 0.0 && alloc > total_assets * max_vault_allocation_share {
-            return false;
-        }
+fn moved(alloc: f64, protocol: &ProtocolState) -> f64 {
+    (alloc - protocol.our_balance.as_f64()).abs()
+}
+
+/// Per-protocol part of `is_valid_allocation`; everything but turnover
+fn is_valid_protocol_allocation(
+    alloc: f64,
+    protocol: &ProtocolState,
+    is_blocked: bool,
+    max_pool_share: f64,
+    min_allocation: f64,
+    max_vault_allocation_share: f64,
+    total_assets: f64,
+) -> bool {
+    let our_balance = protocol.our_balance.as_f64();
+    if is_blocked && alloc > our_balance {
+        return false;
+    }
+
+    let delta = alloc - our_balance;
+    let new_pool_supply = protocol.pool_supply.as_f64() + delta;
+    if new_pool_supply > 0.0 && alloc > new_pool_supply * max_pool_share {
+        return false;
+    }
+
+    if alloc > 0.0 && alloc < min_allocation {
+        return false;
+    }
+
+    // Max vault allocation share constraint: no single protocol can have more than X% of vault
+    if total_assets > 0.0 && alloc > total_assets * max_vault_allocation_share {
+        return false;
     }
 
     true
 }
 
+// ============================================================================
+// WAD Normalization
+// ============================================================================
+
+/// 1.0 in WAD (1e18) fixed point
+pub const WAD: u128 = 1_000_000_000_000_000_000;
+
+/// Split `total` in proportion to `shares` with the largest-remainder method
+///
+/// Each part is floor(total * share / sum). The units lost to flooring (fewer
+/// than `shares.len()`) go one each to the largest remainders, ties to the
+/// lower index, so the parts sum to exactly `total` unless all shares are zero.
+fn apportion(shares: &[U256], total: U256) -> Vec<U256> {
+    let sum = shares.iter().fold(U512::zero(), |acc, &s| acc + U512::from(s));
+    if sum.is_zero() {
+        return vec![U256::zero(); shares.len()];
+    }
+
+    let mut parts = Vec::with_capacity(shares.len());
+    let mut remainders = Vec::with_capacity(shares.len());
+    for (i, &share) in shares.iter().enumerate() {
+        let product = share.full_mul(total);
+        // part <= total, so it always fits
+        parts.push(U256::try_from(product / sum).unwrap_or(total));
+        remainders.push((product % sum, i));
+    }
+
+    let assigned = parts.iter().fold(U256::zero(), |acc, &p| acc + p);
+    let missing = (total - assigned).low_u64() as usize;
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for &(_, i) in remainders.iter().take(missing) {
+        parts[i] += U256::one();
+    }
+    parts
+}
+
+/// WAD weights in proportion to `weights` that sum to exactly 1e18
+///
+/// Negative and NaN weights count as zero; all-zero weights stay zero.
+fn weights_to_wad(weights: &[f64]) -> Vec<U256> {
+    let shares: Vec<U256> = weights.iter()
+        .map(|&w| U256::from((w.max(0.0) * WAD as f64).round() as u128))
+        .collect();
+    apportion(&shares, U256::from(WAD))
+}
+
+/// Exact WAD weights for `weights` and the integer allocations of
+/// `total_assets` they imply, which sum to exactly `total_assets`
+fn integer_allocation(weights: &[f64], total_assets: U256) -> (Vec<U256>, Vec<U256>) {
+    let weights_wad = weights_to_wad(weights);
+    let allocations = apportion(&weights_wad, total_assets);
+    (weights_wad, allocations)
+}
+
+fn format_hex(values: &[U256]) -> Vec<String> {
+    values.iter().map(|v| format!("0x{:064x}", v)).collect()
+}
+
 // ============================================================================
 // Optimizer
 // ============================================================================
 
 fn optimize(
-    total_assets: f64,
+    total_assets_units: U256,
     protocols: &[ProtocolState],
     blocked_mask: u8,
     config: &OptimizerConfig,
     irm_params: Option<&[IRMParams]>,
-) -> Result<OptimizationResult, String> {
+    asset_decimals: u8,
+) -> Result<OptimizationResult, ModuleError> {
     let start_time = std::time::Instant::now();
     let n_protocols = protocols.len();
-
-    log_info!("Starting optimization for {} protocols with {}% step", n_protocols, config.step_pct);
-    log_info!("Total assets: {:.2}", total_assets);
-
-    let use_bounded_grid = total_assets > 0.0;
-    let weights = if use_bounded_grid {
-        let max_allocs: Vec<f64> = protocols.iter()
-            .map(|p| p.pool_supply * config.max_pool_share / (1.0 - config.max_pool_share))
-            .collect();
-
-        // Calculate vault allocation cap in percentage (e.g., 0.4 -> 40%)
-        let vault_cap_pct = (config.max_vault_allocation_share * 100.0) as usize;
-
-        let mut max_weights_pct: Vec<usize> = max_allocs.iter()
-            .map(|ma| {
-                let pool_cap = ((ma / total_assets * 100.0) as usize + 1).min(100);
-                pool_cap.min(vault_cap_pct) // Apply the stricter of pool cap and vault cap
-            })
-            .collect();
-
-        let current_balances: Vec<f64> = protocols.iter().map(|p| p.our_balance).collect();
-        for i in 0..n_protocols {
-            if (blocked_mask & (1 << i)) != 0 {
-                max_weights_pct[i] = ((current_balances[i] / total_assets * 100.0) as usize).max(0);
+    // The search and constraints work on f64; final amounts are re-derived exactly below
+    let total_assets = u256_to_f64(total_assets_units);
+    let raw = RawConfig::new(config, n_protocols, total_assets, asset_decimals);
+
+    log_info!("Starting optimization";
+        protocols = n_protocols, step_pct = config.step_pct, total_assets = total_assets,
+        solver = format!("{:?}", config.solver));
+
+    let greedy = match config.solver {
+        Solver::Greedy if total_assets > 0.0 => {
+            let search = water_fill(total_assets, protocols, blocked_mask, config, irm_params, &raw);
+            if search.is_none() {
+                log_warn!("Greedy solver found no allocation, falling back to the grid");
             }
+            search
         }
-
-        log_info!("Using bounded grid with max weights: {:?} (vault cap: {}%)", max_weights_pct, vault_cap_pct);
-        let w = generate_bounded_weight_grid(n_protocols, config.step_pct, &max_weights_pct);
-        log_info!("Generated {} bounded combinations", w.len());
-        w
-    } else {
-        let w = generate_weight_grid(n_protocols, config.step_pct);
-        log_info!("Generated {} full combinations", w.len());
-        w
+        _ => None,
     };
+    let search = match greedy {
+        Some(search) => search,
+        None => grid_search(total_assets, protocols, blocked_mask, config, irm_params, &raw)?,
+    };
+    let n_scenarios = search.scenarios_evaluated;
+    let n_nodes = search.nodes_visited;
 
-    let n_scenarios = weights.len();
-    log_info!("Evaluating {} scenarios...", n_scenarios);
+    let current_balances: Vec<f64> = protocols.iter().map(|p| p.our_balance.as_f64()).collect();
+    let current_return_12h = is_valid_allocation(&current_balances, protocols, blocked_mask, config.max_pool_share, raw.min_allocation, config.max_vault_allocation_share, total_assets, raw.max_turnover)
+        .then(|| projected_return_12h(&current_balances, &projected_apys(&current_balances, protocols, irm_params)));
 
-    if n_scenarios == 0 {
-        return Err("No valid weight combinations generated".to_string());
-    }
+    if let Some(Candidate { allocations, apys, return_12h: best_return, movement_cost }) = search.best {
+        let weights: Vec<f64> = if total_assets > 0.0 {
+            allocations.iter().map(|&a| a / total_assets).collect()
+        } else {
+            vec![0.0; n_protocols]
+        };
 
-    let mut best_return = f64::NEG_INFINITY;
-    let mut best_allocations: Option<Vec<f64>> = None;
-    let mut best_apys: Option<Vec<f64>> = None;
-    let mut valid_count = 0;
+        let weighted_apy = if total_assets > 0.0 {
+            allocations.iter().zip(apys.iter()).map(|(&a, &apy)| a * apy).sum::<f64>() / total_assets
+        } else {
+            0.0
+        };
 
-    for weight_combo in weights.iter() {
-        let allocations: Vec<f64> = weight_combo.iter().map(|&w| w * total_assets).collect();
+        let elapsed_ms = start_time.elapsed().as_secs_f64() * 1000.0;
 
-        if !is_valid_allocation(&allocations, protocols, blocked_mask, config.max_pool_share, config.min_allocation, config.max_vault_allocation_share, total_assets) {
-            continue;
-        }
+        let (weights_wad, allocation_units) = integer_allocation(&weights, total_assets_units);
 
-        valid_count += 1;
-
-        let apys: Vec<f64> = if let Some(irm_params_slice) = irm_params {
-            allocations.iter().zip(protocols.iter()).zip(irm_params_slice.iter())
-                .map(|((&alloc, protocol), irm)| {
-                    let delta = alloc - protocol.our_balance;
-                    calc_supply_apy_with_irm(protocol, delta, irm)
-                })
-                .collect()
-        } else {
-            allocations.iter().zip(protocols.iter())
-                .map(|(&alloc, protocol)| {
-                    let delta = alloc - protocol.our_balance;
-                    calc_supply_apy(protocol, delta)
-                })
-                .collect()
-        };
-
-        let time_factor = 12.0 / 8760.0;
-        let return_12h: f64 = allocations.iter().zip(apys.iter())
-            .map(|(&alloc, &apy)| alloc * apy * time_factor)
-            .sum();
-
-        if return_12h > best_return {
-            best_return = return_12h;
-            best_allocations = Some(allocations);
-            best_apys = Some(apys);
-        }
-    }
-
-    log_info!("Valid scenarios: {} ({:.1}%)", valid_count, 100.0 * valid_count as f64 / n_scenarios as f64);
-
-    if let (Some(allocations), Some(apys)) = (best_allocations, best_apys) {
-        let weights: Vec<f64> = if total_assets > 0.0 {
-            allocations.iter().map(|&a| a / total_assets).collect()
-        } else {
-            vec![0.0; n_protocols]
-        };
-
-        let weighted_apy = if total_assets > 0.0 {
-            allocations.iter().zip(apys.iter()).map(|(&a, &apy)| a * apy).sum::<f64>() / total_assets
-        } else {
-            0.0
-        };
-
-        let elapsed_ms = start_time.elapsed().as_secs_f64() * 1000.0;
-
-        let allocations_hex: Vec<String> = allocations.iter()
-            .map(|&a| format!("0x{:064x}", (a as u128).min(u128::MAX)))
-            .collect();
-
-        const WAD_F64: f64 = 1e18;
-        let weights_wad: Vec<String> = weights.iter()
-            .map(|&w| format!("0x{:064x}", (w * WAD_F64) as u128))
-            .collect();
-
-        log_info!("Optimization complete: return={:.4}, apy={:.4}%, time={:.2}ms",
-            best_return, weighted_apy * 100.0, elapsed_ms);
+        log_info!("Optimization complete";
+            expected_return_12h = best_return, apy = weighted_apy, time_ms = elapsed_ms);
 
         Ok(OptimizationResult {
-            allocations: allocations_hex,
-            allocations_decimal: allocations,
-            weights: weights_wad,
+            total_assets: TokenAmount(total_assets_units),
+            allocations: format_hex(&allocation_units),
+            allocations_decimal: allocation_units.iter().map(|&a| u256_to_f64(a)).collect(),
+            weights: format_hex(&weights_wad),
             weights_decimal: weights,
             expected_return_12h: best_return,
             expected_apy_weighted: weighted_apy,
             apys,
             scenarios_evaluated: n_scenarios,
-            time_ms: elapsed_ms,
+            nodes_visited: n_nodes,
+            current_return_12h,
+            movement_cost,
+            gas: None,
+            net_return_12h: best_return - movement_cost,
         })
     } else {
         // No valid allocation found - return current allocation
-        let current_balances: Vec<f64> = protocols.iter().map(|p| p.our_balance).collect();
         let current_apys: Vec<f64> = protocols.iter().map(|p| p.current_apy).collect();
         let weights_decimal: Vec<f64> = if total_assets > 0.0 {
             current_balances.iter().map(|&b| b / total_assets).collect()
@@ -878,30 +1476,510 @@ fn optimize(
             vec![0.0; n_protocols]
         };
 
-        let elapsed_ms = start_time.elapsed().as_secs_f64() * 1000.0;
-
-        let allocations_hex: Vec<String> = current_balances.iter()
-            .map(|&a| format!("0x{:064x}", (a as u128).min(u128::MAX)))
-            .collect();
-
-        const WAD_F64: f64 = 1e18;
-        let weights_wad: Vec<String> = weights_decimal.iter()
-            .map(|&w| format!("0x{:064x}", (w * WAD_F64) as u128))
-            .collect();
+        let (weights_wad, allocation_units) = integer_allocation(&weights_decimal, total_assets_units);
 
         log_info!("No valid allocation found, returning current state");
 
         Ok(OptimizationResult {
-            allocations: allocations_hex,
-            allocations_decimal: current_balances,
-            weights: weights_wad,
+            total_assets: TokenAmount(total_assets_units),
+            allocations: format_hex(&allocation_units),
+            allocations_decimal: allocation_units.iter().map(|&a| u256_to_f64(a)).collect(),
+            weights: format_hex(&weights_wad),
             weights_decimal,
             expected_return_12h: 0.0,
             expected_apy_weighted: 0.0,
             apys: current_apys,
             scenarios_evaluated: n_scenarios,
-            time_ms: elapsed_ms,
+            nodes_visited: n_nodes,
+            current_return_12h,
+            movement_cost: 0.0,
+            gas: None,
+            net_return_12h: 0.0,
+        })
+    }
+}
+
+/// Config amounts in raw asset units, as the solvers use them
+struct RawConfig {
+    min_allocation: f64,
+    max_turnover: f64,
+    costs: Vec<MovementCost>,
+}
+
+impl RawConfig {
+    fn new(config: &OptimizerConfig, n_protocols: usize, total_assets: f64, asset_decimals: u8) -> Self {
+        Self {
+            min_allocation: config.min_allocation_units(asset_decimals),
+            max_turnover: config.max_turnover_units(total_assets, asset_decimals),
+            costs: (0..n_protocols)
+                .map(|i| config.protocol_costs.get(i).map_or_else(MovementCost::default, |c| MovementCost::new(c, asset_decimals)))
+                .collect(),
+        }
+    }
+}
+
+/// Best allocation found by a solver
+struct Candidate {
+    allocations: Vec<f64>,
+    apys: Vec<f64>,
+    return_12h: f64,
+    movement_cost: f64,
+}
+
+impl Candidate {
+    fn new(allocations: Vec<f64>, protocols: &[ProtocolState], irm_params: Option<&[IRMParams]>, costs: &[MovementCost]) -> Self {
+        let apys = projected_apys(&allocations, protocols, irm_params);
+        let return_12h = projected_return_12h(&allocations, &apys);
+        let movement_cost = total_movement_cost(&allocations, protocols, costs);
+        Self { allocations, apys, return_12h, movement_cost }
+    }
+
+    /// What the solvers maximize: the 12h return net of movement costs
+    fn objective(&self) -> f64 {
+        self.return_12h - self.movement_cost
+    }
+}
+
+struct Search {
+    best: Option<Candidate>,
+    scenarios_evaluated: usize,
+    nodes_visited: usize,
+}
+
+/// Upper bound of each protocol's weight on the bounded grid, in percent
+fn max_weights_pct(total_assets: f64, protocols: &[ProtocolState], blocked_mask: u8, config: &OptimizerConfig) -> Vec<usize> {
+    let max_allocs: Vec<f64> = protocols.iter()
+        .map(|p| p.pool_supply.as_f64() * config.max_pool_share / (1.0 - config.max_pool_share))
+        .collect();
+
+    // Calculate vault allocation cap in percentage (e.g., 0.4 -> 40%)
+    let vault_cap_pct = (config.max_vault_allocation_share * 100.0) as usize;
+
+    let mut max_weights_pct: Vec<usize> = max_allocs.iter()
+        .map(|ma| {
+            let pool_cap = ((ma / total_assets * 100.0) as usize + 1).min(100);
+            pool_cap.min(vault_cap_pct) // Apply the stricter of pool cap and vault cap
+        })
+        .collect();
+
+    for (i, protocol) in protocols.iter().enumerate() {
+        if (blocked_mask & (1 << i)) != 0 {
+            max_weights_pct[i] = (protocol.our_balance.as_f64() / total_assets * 100.0) as usize;
+        }
+    }
+    max_weights_pct
+}
+
+/// Best valid combination on the `stepPct` grid
+///
+/// With assets to place, the bounded grid is searched by branch and bound;
+/// otherwise every combination of the full grid is evaluated.
+fn grid_search(
+    total_assets: f64,
+    protocols: &[ProtocolState],
+    blocked_mask: u8,
+    config: &OptimizerConfig,
+    irm_params: Option<&[IRMParams]>,
+    raw: &RawConfig,
+) -> Result<Search, ModuleError> {
+    if total_assets > 0.0 {
+        return branch_and_bound(total_assets, protocols, blocked_mask, config, irm_params, raw);
+    }
+
+    let mut best: Option<Candidate> = None;
+    let mut valid_count = 0;
+    let mut allocations = vec![0.0; protocols.len()];
+
+    let n_visited = visit_weight_grid(protocols.len(), config.step_pct, |weight_combo| {
+        for (alloc, &w) in allocations.iter_mut().zip(weight_combo) {
+            *alloc = w * total_assets;
+        }
+
+        if !is_valid_allocation(&allocations, protocols, blocked_mask, config.max_pool_share, raw.min_allocation, config.max_vault_allocation_share, total_assets, raw.max_turnover) {
+            return;
+        }
+
+        valid_count += 1;
+
+        let candidate = Candidate::new(allocations.clone(), protocols, irm_params, &raw.costs);
+        if candidate.objective() > best.as_ref().map_or(f64::NEG_INFINITY, Candidate::objective) {
+            best = Some(candidate);
+        }
+    });
+
+    if n_visited == 0 {
+        return Err(ModuleError::Infeasible("No valid weight combinations generated".to_string()));
+    }
+
+    log_info!("Valid scenarios: {} ({:.1}%)", valid_count, 100.0 * valid_count as f64 / n_visited as f64;
+        valid = valid_count, visited = n_visited);
+
+    Ok(Search { best, scenarios_evaluated: valid_count, nodes_visited: n_visited })
+}
+
+/// Exact branch-and-bound search of the bounded grid
+///
+/// Visits the bounded grid's combinations in generation order, but every
+/// constraint of `is_valid_allocation` is per protocol, so each protocol only
+/// branches into the step counts it may hold on its own. The 12h return is a
+/// sum of per-protocol terms, so the most the remaining protocols can add with
+/// the steps left is known up front (a table filled from the last protocol
+/// back), and a subtree is dropped once it cannot reach the grid optimum. The
+/// result is the exhaustive grid's best point, and of equally good points the
+/// first one, as before; only near-optimal paths are walked, so time and
+/// memory stay small however many pools there are.
+///
+/// Turnover is the one constraint across protocols. A second table holds the
+/// least the remaining protocols must move to hold the steps left, so
+/// subtrees that would break the limit are dropped too. If the limit rules the
+/// unconstrained optimum out, the search runs again, dropping subtrees that
+/// cannot beat the best point found so far instead.
+fn branch_and_bound(
+    total_assets: f64,
+    protocols: &[ProtocolState],
+    blocked_mask: u8,
+    config: &OptimizerConfig,
+    irm_params: Option<&[IRMParams]>,
+    raw: &RawConfig,
+) -> Result<Search, ModuleError> {
+    let n_protocols = protocols.len();
+    let total_steps = 100 / config.step_pct;
+    let max_weights_pct = max_weights_pct(total_assets, protocols, blocked_mask, config);
+    log_info!("Using bounded grid with max weights: {:?} (vault cap: {}%)",
+        max_weights_pct, (config.max_vault_allocation_share * 100.0) as usize);
+
+    let max_steps: Vec<usize> = max_weights_pct.iter()
+        .map(|w| (w / config.step_pct).min(total_steps))
+        .collect();
+    if n_protocols == 0 || max_steps.iter().sum::<usize>() < total_steps {
+        return Err(ModuleError::Infeasible("No valid weight combinations generated".to_string()));
+    }
+
+    // 12h return net of movement cost of each protocol at each step count,
+    // None where it breaks a constraint
+    let returns: Vec<Vec<Option<f64>>> = protocols.iter().enumerate()
+        .map(|(i, protocol)| {
+            let is_blocked = (blocked_mask & (1 << i)) != 0;
+            let irm = irm_params.and_then(|params| params.get(i));
+            (0..=max_steps[i])
+                .map(|k| {
+                    let alloc = grid_weight(k, config.step_pct) * total_assets;
+                    is_valid_protocol_allocation(alloc, protocol, is_blocked, config.max_pool_share,
+                        raw.min_allocation, config.max_vault_allocation_share, total_assets)
+                        .then(|| projected_return_12h(&[alloc], &[projected_apy(alloc, protocol, irm)]) - raw.costs[i].of(alloc, protocol))
+                })
+                .collect()
         })
+        .collect();
+
+    // Amount each protocol moves at each step count
+    let moves: Vec<Vec<f64>> = protocols.iter().enumerate()
+        .map(|(i, protocol)| (0..=max_steps[i]).map(|k| moved(grid_weight(k, config.step_pct) * total_assets, protocol)).collect())
+        .collect();
+
+    // bounds[d][r]: most protocols d.. can return holding exactly r steps,
+    // -inf if they cannot hold them; least_moved[d][r]: least they move doing so
+    let mut bounds = vec![vec![f64::NEG_INFINITY; total_steps + 1]; n_protocols + 1];
+    let mut least_moved = vec![vec![f64::INFINITY; total_steps + 1]; n_protocols + 1];
+    bounds[n_protocols][0] = 0.0;
+    least_moved[n_protocols][0] = 0.0;
+    for d in (0..n_protocols).rev() {
+        let (head, tail) = bounds.split_at_mut(d + 1);
+        let (moved_head, moved_tail) = least_moved.split_at_mut(d + 1);
+        for (r, (bound, least)) in head[d].iter_mut().zip(moved_head[d].iter_mut()).enumerate() {
+            for (k, value) in returns[d].iter().enumerate().take(r + 1) {
+                if let Some(value) = value {
+                    *bound = bound.max(value + tail[0][r - k]);
+                    *least = least.min(moves[d][k] + moved_tail[0][r - k]);
+                }
+            }
+        }
+    }
+
+    let optimum = bounds[0][total_steps];
+    let mut search = BranchAndBound {
+        returns: &returns,
+        bounds: &bounds,
+        moves: &moves,
+        least_moved: &least_moved,
+        max_turnover: raw.max_turnover,
+        // The slack keeps rounding in the table from dropping an optimal point
+        floor: optimum - optimum.abs() * 1e-12,
+        raise_floor: false,
+        steps: Vec::with_capacity(n_protocols),
+        best: None,
+        evaluated: 0,
+        visited: 0,
+    };
+    if optimum > f64::NEG_INFINITY {
+        search.visit(0, total_steps, 0.0, 0.0);
+        if search.best.is_none() && raw.max_turnover.is_finite() {
+            log_info!("Turnover limit rules out the unconstrained optimum, searching below it");
+            search.floor = f64::NEG_INFINITY;
+            search.raise_floor = true;
+            search.visit(0, total_steps, 0.0, 0.0);
+        }
+    }
+    log_info!("Evaluated {} grid points", search.evaluated; evaluated = search.evaluated, visited = search.visited);
+
+    let best = search.best.map(|(_, steps)| {
+        let allocations = steps.iter().map(|&k| grid_weight(k, config.step_pct) * total_assets).collect();
+        Candidate::new(allocations, protocols, irm_params, &raw.costs)
+    });
+    Ok(Search { best, scenarios_evaluated: search.evaluated, nodes_visited: search.visited })
+}
+
+struct BranchAndBound<'a> {
+    returns: &'a [Vec<Option<f64>>],
+    bounds: &'a [Vec<f64>],
+    moves: &'a [Vec<f64>],
+    least_moved: &'a [Vec<f64>],
+    max_turnover: f64,
+    /// Smallest return worth walking towards
+    floor: f64,
+    /// Raise `floor` to each new best point
+    raise_floor: bool,
+    /// Step counts of the protocols above the current depth
+    steps: Vec<usize>,
+    /// Best return and step counts so far
+    best: Option<(f64, Vec<usize>)>,
+    /// Complete grid points whose return was compared
+    evaluated: usize,
+    /// Tree nodes walked, complete grid points included
+    visited: usize,
+}
+
+impl BranchAndBound<'_> {
+    fn visit(&mut self, depth: usize, remaining: usize, partial: f64, moved: f64) {
+        self.visited += 1;
+        if depth == self.returns.len() - 1 {
+            if let Some(&Some(value)) = self.returns[depth].get(remaining) {
+                // Summed in protocol order, exactly as `turnover` and `projected_return_12h` do
+                if moved + self.moves[depth][remaining] > self.max_turnover {
+                    return;
+                }
+                self.evaluated += 1;
+                let total = partial + value;
+                if total > self.best.as_ref().map_or(f64::NEG_INFINITY, |(best, _)| *best) {
+                    let mut steps = self.steps.clone();
+                    steps.push(remaining);
+                    self.best = Some((total, steps));
+                    if self.raise_floor {
+                        self.floor = total - total.abs() * 1e-12;
+                    }
+                }
+            }
+            return;
+        }
+
+        // The bound is -inf where the protocols left cannot hold the steps left
+        if partial + self.bounds[depth][remaining] < self.floor {
+            return;
+        }
+        // With the same slack against rounding in the table
+        if moved + self.least_moved[depth][remaining] > self.max_turnover * (1.0 + 1e-12) {
+            return;
+        }
+
+        let returns = self.returns;
+        for (k, value) in returns[depth].iter().enumerate().take(remaining + 1) {
+            if let Some(value) = value {
+                self.steps.push(k);
+                self.visit(depth + 1, remaining - k, partial + value, moved + self.moves[depth][k]);
+                self.steps.pop();
+            }
+        }
+    }
+}
+
+/// Greedy search on the `stepPct` grid by marginal 12h return
+///
+/// Starting from nothing allocated, every step of `stepPct` of the vault goes
+/// to the protocol whose 12h return rises most per step. Opening a protocol
+/// jumps straight to its smallest valid allocation (`minAllocation`), and no
+/// protocol goes past the bounded grid's cap or its own constraints, and no
+/// step goes where it would break the turnover limit. Returns `None` if the
+/// steps cannot all be placed.
+fn water_fill(
+    total_assets: f64,
+    protocols: &[ProtocolState],
+    blocked_mask: u8,
+    config: &OptimizerConfig,
+    irm_params: Option<&[IRMParams]>,
+    raw: &RawConfig,
+) -> Option<Search> {
+    let total_steps = 100 / config.step_pct;
+    let step_alloc = |steps: usize| grid_weight(steps, config.step_pct) * total_assets;
+    let max_weights_pct = max_weights_pct(total_assets, protocols, blocked_mask, config);
+    let mut evaluated = 0;
+
+    // Smallest and largest valid non-zero step count of each protocol; every
+    // per-protocol constraint but minAllocation is an upper bound
+    let ranges: Vec<Option<(usize, usize)>> = protocols.iter().enumerate()
+        .map(|(i, protocol)| {
+            let is_blocked = (blocked_mask & (1 << i)) != 0;
+            let cap = (max_weights_pct[i] / config.step_pct).min(total_steps);
+            let valid: Vec<usize> = (1..=cap)
+                .filter(|&k| is_valid_protocol_allocation(step_alloc(k), protocol, is_blocked, config.max_pool_share,
+                    raw.min_allocation, config.max_vault_allocation_share, total_assets))
+                .collect();
+            Some((*valid.first()?, *valid.last()?))
+        })
+        .collect();
+
+    let value = |i: usize, steps: usize| {
+        let alloc = step_alloc(steps);
+        let irm = irm_params.and_then(|params| params.get(i));
+        projected_return_12h(&[alloc], &[projected_apy(alloc, &protocols[i], irm)]) - raw.costs[i].of(alloc, &protocols[i])
+    };
+
+    // Steps only ever add to a protocol, so what is placed above the current
+    // balances only grows. Once every step is placed, the turnover is twice
+    // that excess less the assets held outside the protocols.
+    let excess = |i: usize, steps: usize| (step_alloc(steps) - protocols[i].our_balance.as_f64()).max(0.0);
+    let held: f64 = protocols.iter().map(|p| p.our_balance.as_f64()).sum();
+    let max_excess = (raw.max_turnover + total_assets - held) / 2.0;
+    let mut total_excess = 0.0;
+
+    let mut steps = vec![0usize; protocols.len()];
+    // Holding nothing is a move too: it withdraws the current balance
+    let mut values: Vec<f64> = (0..protocols.len()).map(|i| value(i, 0)).collect();
+    let mut remaining = total_steps;
+    while remaining > 0 {
+        // (protocol, step count to move to, value there, gain per step)
+        let mut best_move: Option<(usize, usize, f64, f64)> = None;
+        for (i, range) in ranges.iter().enumerate() {
+            let Some((min_steps, max_steps)) = *range else { continue };
+            let target = if steps[i] == 0 { min_steps } else { steps[i] + 1 };
+            if target > max_steps || target - steps[i] > remaining {
+                continue;
+            }
+            if total_excess - excess(i, steps[i]) + excess(i, target) > max_excess {
+                continue;
+            }
+            let target_value = value(i, target);
+            evaluated += 1;
+            let gain = (target_value - values[i]) / (target - steps[i]) as f64;
+            if best_move.map_or(true, |(_, _, _, best_gain)| gain > best_gain) {
+                best_move = Some((i, target, target_value, gain));
+            }
+        }
+
+        let Some((i, target, target_value, _)) = best_move else {
+            log_info!("Greedy fill stuck"; steps = format!("{:?}", steps), remaining = remaining);
+            return None;
+        };
+        remaining -= target - steps[i];
+        total_excess += excess(i, target) - excess(i, steps[i]);
+        steps[i] = target;
+        values[i] = target_value;
+    }
+
+    let allocations: Vec<f64> = steps.iter().map(|&k| step_alloc(k)).collect();
+    log_info!("Greedy allocation found"; steps = format!("{:?}", steps), evaluated = evaluated);
+    // The running excess can round differently from the sum `is_valid_allocation` checks
+    if turnover(&allocations, protocols) > raw.max_turnover {
+        log_info!("Greedy allocation breaks the turnover limit after rounding");
+        return None;
+    }
+
+    Some(Search { best: Some(Candidate::new(allocations, protocols, irm_params, &raw.costs)), scenarios_evaluated: 1, nodes_visited: evaluated })
+}
+
+/// Supply APY of each protocol after moving to `allocations`
+fn projected_apys(allocations: &[f64], protocols: &[ProtocolState], irm_params: Option<&[IRMParams]>) -> Vec<f64> {
+    allocations.iter().zip(protocols.iter()).enumerate()
+        .map(|(i, (&alloc, protocol))| projected_apy(alloc, protocol, irm_params.and_then(|params| params.get(i))))
+        .collect()
+}
+
+/// Supply APY of `protocol` after moving to `alloc`
+fn projected_apy(alloc: f64, protocol: &ProtocolState, irm: Option<&IRMParams>) -> f64 {
+    let delta = alloc - protocol.our_balance.as_f64();
+    match irm {
+        Some(irm) => calc_supply_apy_with_irm(protocol, delta, irm),
+        None => calc_supply_apy(protocol, delta),
+    }
+}
+
+fn projected_return_12h(allocations: &[f64], apys: &[f64]) -> f64 {
+    let time_factor = 12.0 / 8760.0;
+    allocations.iter().zip(apys.iter())
+        .map(|(&alloc, &apy)| alloc * apy * time_factor)
+        .sum()
+}
+
+// ============================================================================
+// Movement Costs
+// ============================================================================
+
+/// `ProtocolCost` in raw asset units
+#[derive(Debug, Clone, Copy, Default)]
+struct MovementCost {
+    fixed: f64,
+    rate: f64,
+}
+
+impl MovementCost {
+    fn new(cost: &ProtocolCost, asset_decimals: u8) -> Self {
+        Self { fixed: to_raw_units(cost.fixed, asset_decimals), rate: cost.proportional_bps / 10_000.0 }
+    }
+
+    /// Cost of moving `protocol` from its current balance to `alloc`
+    fn of(&self, alloc: f64, protocol: &ProtocolState) -> f64 {
+        let moved = moved(alloc, protocol);
+        // Less than one raw unit is float noise, not a transfer
+        if moved < 1.0 {
+            return 0.0;
+        }
+        self.fixed + moved * self.rate
+    }
+}
+
+fn total_movement_cost(allocations: &[f64], protocols: &[ProtocolState], costs: &[MovementCost]) -> f64 {
+    allocations.iter().zip(protocols.iter()).zip(costs.iter())
+        .map(|((&alloc, protocol), cost)| cost.of(alloc, protocol))
+        .sum()
+}
+
+/// Gas cost of `gas_units` at `gas_price_wei`, in raw asset units
+fn gas_cost(gas: &GasConfig, gas_units: u64, gas_price_wei: U256, asset_decimals: u8) -> GasCost {
+    let wei = U256::from(gas_units).saturating_mul(gas_price_wei);
+    let eth = u256_to_f64(wei) / 1e18;
+    GasCost {
+        gas_units,
+        gas_price_wei: TokenAmount(gas_price_wei),
+        cost: to_raw_units(eth * gas.asset_per_eth, asset_decimals),
+    }
+}
+
+/// Price `executeRebalance(weights)` with the state `read_pinned_state` read
+///
+/// Gas comes from `gasLimit` or `eth_estimateGas` at the pinned block's number.
+fn estimate_gas_cost(client: &EthClient, gas: &GasConfig, pinned: &PinnedState, vault: Address, weights: &[String]) -> Result<GasCost, ModuleError> {
+    let gas_price_wei = pinned.gas_price_wei
+        .ok_or_else(|| ModuleError::Internal("Gas price was not read".to_string()))?;
+    let gas_units = match gas.gas_limit {
+        Some(limit) => limit,
+        None => {
+            let from = gas.from.as_deref().map(parse_address).transpose()?;
+            let call_data = vault_tx::execute_rebalance_calldata(weights)?;
+            client.eth_estimate_gas(from, vault, &call_data, pinned.estimate_block)
+                .map_err(|e| e.context("Failed to estimate executeRebalance gas"))?
+        }
+    };
+
+    let cost = gas_cost(gas, gas_units, gas_price_wei, pinned.asset_decimals);
+    log_info!("Priced rebalance gas";
+        gas_units = gas_units, gas_price_wei = gas_price_wei.to_string(), cost = cost.cost);
+    Ok(cost)
+}
+
+/// Price the gas from `gasLimit` and `gasPriceWei`, which legacy mode cannot read on chain
+fn configured_gas_cost(gas: &GasConfig, asset_decimals: u8) -> Result<GasCost, ModuleError> {
+    match (gas.gas_limit, gas.gas_price_wei) {
+        (Some(gas_units), Some(price)) => Ok(gas_cost(gas, gas_units, price.0, asset_decimals)),
+        _ => Err(ModuleError::InvalidInput("config.gas needs gasLimit and gasPriceWei in legacy mode".to_string())),
     }
 }
 
@@ -913,40 +1991,40 @@ fn transform_snapshot_to_input(snapshot: vault_reader::VaultSnapshot) -> (Optimi
     const WAD: f64 = 1e18;
     const BPS: f64 = 10000.0;
 
-    let total_assets = snapshot.total_assets.low_u128() as f64;
+    let total_assets = TokenAmount(snapshot.total_assets);
     let mut protocols: Vec<ProtocolState> = Vec::new();
     let mut irm_params_list: Vec<IRMParams> = Vec::new();
 
     for p in snapshot.protocols.iter() {
         let current_apy = if p.protocol_type == PROTO_MORPHO {
             calc_dilution_current_apy(
-                p.meta_total_assets.low_u128() as f64,
-                p.meta_total_supply.low_u128() as f64,
-                p.meta_last_total_assets.low_u128() as f64,
+                u256_to_f64(p.meta_total_assets),
+                u256_to_f64(p.meta_total_supply),
+                u256_to_f64(p.meta_last_total_assets),
                 p.meta_last_update,
                 snapshot.snapshot_timestamp,
             )
         } else {
-            p.current_apy_wad.low_u128() as f64 / WAD
+            u256_to_f64(p.current_apy_wad) / WAD
         };
 
         protocols.push(ProtocolState {
-            our_balance: p.our_balance.low_u128() as f64,
-            pool_supply: p.pool_total_supply.low_u128() as f64,
-            pool_borrow: p.pool_total_borrow.low_u128() as f64,
-            utilization: p.utilization_wad.low_u128() as f64 / WAD,
+            our_balance: TokenAmount(p.our_balance),
+            pool_supply: TokenAmount(p.pool_total_supply),
+            pool_borrow: TokenAmount(p.pool_total_borrow),
+            utilization: u256_to_f64(p.utilization_wad) / WAD,
             current_apy,
             is_blocked: false,
             protocol_type: p.protocol_type,
         });
 
         irm_params_list.push(IRMParams {
-            kink1: p.irm.kink1_bps.low_u128() as f64 / BPS,
-            rate_at_kink1: p.irm.rate_at_kink1_bps.low_u128() as f64 / BPS,
-            kink2: p.irm.kink2_bps.low_u128() as f64 / BPS,
-            rate_at_kink2: p.irm.rate_at_kink2_bps.low_u128() as f64 / BPS,
-            rate_at_max: p.irm.rate_at_max_bps.low_u128() as f64 / BPS,
-            reserve_factor: p.irm.reserve_factor_bps.low_u128() as f64 / BPS,
+            kink1: u256_to_f64(p.irm.kink1_bps) / BPS,
+            rate_at_kink1: u256_to_f64(p.irm.rate_at_kink1_bps) / BPS,
+            kink2: u256_to_f64(p.irm.kink2_bps) / BPS,
+            rate_at_kink2: u256_to_f64(p.irm.rate_at_kink2_bps) / BPS,
+            rate_at_max: u256_to_f64(p.irm.rate_at_max_bps) / BPS,
+            reserve_factor: u256_to_f64(p.irm.reserve_factor_bps) / BPS,
         });
     }
 
@@ -955,6 +2033,9 @@ fn transform_snapshot_to_input(snapshot: vault_reader::VaultSnapshot) -> (Optimi
         protocols,
         blocked_mask: snapshot.guard_state.blocked_mask,
         config: None,
+        vault: None,
+        asset_decimals: None,
+        deterministic: None,
     };
 
     (input, irm_params_list)
@@ -964,115 +2045,170 @@ fn transform_snapshot_to_input(snapshot: vault_reader::VaultSnapshot) -> (Optimi
 // Entry Points
 // ============================================================================
 
+/// The `rebalance` action
+pub struct Rebalance;
+
+impl Action for Rebalance {
+    const NAME: &'static str = "rebalance";
+    const DESCRIPTION: &'static str = "Optimize allocations across lending protocols (default action)";
+
+    type Input = RebalanceInput;
+    type Output = RebalanceResult;
+
+    fn run(&self, input: RebalanceInput) -> Result<RebalanceResult, ModuleError> {
+        let config = input.config().cloned().unwrap_or_default();
+        let output = match input {
+            RebalanceInput::Rpc(params) => run_with_rpc(&params)?,
+            RebalanceInput::Legacy(optimizer_input) => run_legacy(*optimizer_input)?,
+        };
+        Ok(output.or_skip(&config))
+    }
+}
+
 /// RPC-enabled mode: fetch data from VaultDataReader and optimize
-pub fn run_with_rpc(input: Value) {
+pub fn run_with_rpc(params: &RpcRebalanceInput) -> Result<RebalanceOutput, ModuleError> {
     log_info!("Running rebalance in RPC-enabled mode");
 
-    let vault_data_reader = input.get("vaultDataReader").and_then(|v| v.as_str()).unwrap_or("");
-    let vault = input.get("vault").and_then(|v| v.as_str()).unwrap_or("");
-    let protocol_types: Vec<u8> = input.get("protocolTypes")
-        .and_then(|v| v.as_array())
-        .map(|arr| arr.iter().filter_map(|v| v.as_u64().map(|n| n as u8)).collect())
-        .unwrap_or_default();
-    let pools: Vec<String> = input.get("pools")
-        .and_then(|v| v.as_array())
-        .map(|arr| arr.iter().filter_map(|v| v.as_str().map(String::from)).collect())
-        .unwrap_or_default();
-    let chain_id = input.get("chainId").and_then(|v| v.as_u64()).unwrap_or(1);
-
-    log_info!("Config: vault={}, protocols={}, chainId={}", vault, protocol_types.len(), chain_id);
-
-    let rpc_config = match RpcConfig::from_env() {
-        Ok(c) => c,
-        Err(e) => {
-            output_error(&e);
-            return;
+    let rpc_config = RpcConfig::from_env()?.with_overrides(params.rpc.as_ref());
+    let output = rebalance_with_rpc(params, &rpc_config)?;
+    log_info!("Optimization successful");
+    Ok(output)
+}
+
+/// What an RPC run reads at the pinned block besides the snapshot
+struct PinnedState {
+    asset_decimals: u8,
+    /// With `config.gas`: `gasPriceWei`, or else the block's base fee plus
+    /// `priorityFeeWei`, or `eth_gasPrice` on chains without a base fee
+    gas_price_wei: Option<U256>,
+    /// Block to run `eth_estimateGas` against: the pinned number, or the
+    /// header's number when the block is pinned by hash
+    estimate_block: BlockTag,
+}
+
+/// Read the [`PinnedState`] in one round-trip
+///
+/// The block header is read for its base fee, and for its number when the run
+/// is pinned by hash, since many nodes reject a block hash for `eth_estimateGas`.
+/// Only a header without a base fee costs a second round-trip, for `eth_gasPrice`.
+/// A pinned hash the node does not know is an `InvalidInput` error rather than
+/// a silent fallback to `latest`.
+fn read_pinned_state(client: &EthClient, asset: Address, gas: Option<&GasConfig>) -> Result<PinnedState, ModuleError> {
+    let reads_gas_price = gas.is_some_and(|gas| gas.gas_price_wei.is_none());
+    let estimates_by_hash = gas.is_some_and(|gas| gas.gas_limit.is_none()) && client.block().hash().is_some();
+    let reads_header = reads_gas_price || estimates_by_hash;
+    let mut requests = vec![vault_reader::asset_decimals_request(client, asset)?];
+    if reads_header {
+        requests.push(EthRequest::get_block(client.block()));
+    }
+
+    let mut results = client.batch(&requests)
+        .map_err(|e| e.context("Failed to read pinned block state"))?
+        .into_iter();
+    let mut next = || results.next()
+        .unwrap_or_else(|| Err(ModuleError::Internal("Missing RPC batch result".to_string())));
+
+    let asset_decimals = next()
+        .and_then(|result| vault_reader::decode_asset_decimals(&parse_bytes(&result)?))
+        .map_err(|e| e.context("Failed to read asset decimals"))?;
+
+    let header = if reads_header {
+        let header = next()
+            .and_then(|result| parse_block_result(&result))
+            .map_err(|e| e.context("Failed to read block header"))?;
+        if let (None, BlockTag::Hash(hash)) = (&header, client.block()) {
+            return Err(ModuleError::InvalidInput(format!("Unknown block hash {:?}", hash)));
         }
+        header
+    } else {
+        None
     };
 
-    log_info!("Fetching vault snapshot...");
-    let snapshot = match vault_reader::get_snapshot(&rpc_config, vault_data_reader, vault, &protocol_types, &pools, chain_id) {
-        Ok(s) => s,
-        Err(e) => {
-            output_error(&format!("Failed to fetch snapshot: {}", e));
-            return;
+    let gas_price_wei = match gas {
+        Some(GasConfig { gas_price_wei: Some(price), .. }) => Some(price.0),
+        Some(gas) => {
+            let price = match header.as_ref().and_then(|block| block.base_fee_per_gas) {
+                Some(base_fee) => base_fee.saturating_add(gas.priority_fee_wei.map_or(U256::zero(), |tip| tip.0)),
+                None => client.eth_gas_price()
+                    .map_err(|e| e.context("Failed to read gas price"))?,
+            };
+            Some(price)
         }
+        None => None,
     };
 
-    log_info!("Snapshot fetched: {} protocols, totalAssets={}", snapshot.protocols.len(), snapshot.total_assets);
+    let estimate_block = match client.block() {
+        block @ BlockTag::Hash(_) => header.map_or(block, |block| BlockTag::Number(block.number)),
+        block => block,
+    };
+    Ok(PinnedState { asset_decimals, gas_price_wei, estimate_block })
+}
+
+/// Fetch the snapshot through `rpc_config`'s transport, transform and optimize
+fn rebalance_with_rpc(params: &RpcRebalanceInput, rpc_config: &RpcConfig) -> Result<RebalanceOutput, ModuleError> {
+    let chain_id = params.chain_id;
+
+    log_info!("Rebalance config"; vault = params.vault, protocols = params.protocol_types.len(), chain_id = chain_id);
+
+    log_info!("Fetching vault snapshot...");
+    // Resolve the block once so every read of this run sees the same state
+    let client = EthClient::new(rpc_config, chain_id).pin_block(params.block()?)
+        .map_err(|e| e.context("Failed to pin block"))?;
+    let pinned_block = client.block();
+
+    let snapshot = vault_reader::get_snapshot(&client, &params.vault_data_reader, &params.vault, &params.protocol_types, &params.pools)
+        .map_err(|e| e.context("Failed to fetch snapshot"))?;
+
+    log_info!("Snapshot fetched";
+        protocols = snapshot.protocols.len(), total_assets = snapshot.total_assets.to_string());
+
+    let config = params.config.clone().unwrap_or_default();
+    let pinned = read_pinned_state(&client, snapshot.asset, config.gas.as_ref())?;
+    let asset_decimals = pinned.asset_decimals;
+    log_info!("Asset decimals"; asset = format!("{:?}", snapshot.asset), decimals = asset_decimals);
 
     let (optimizer_input, irm_params) = transform_snapshot_to_input(snapshot);
     log_info!("Transformed {} protocols with IRM params", optimizer_input.protocols.len());
 
-    let config = if let Some(cfg) = input.get("config") {
-        OptimizerConfig {
-            step_pct: cfg.get("stepPct").and_then(|v| v.as_u64()).unwrap_or(1) as usize,
-            max_pool_share: cfg.get("maxPoolShare").and_then(|v| v.as_f64()).unwrap_or(0.2),
-            min_allocation: cfg.get("minAllocation").and_then(|v| v.as_f64()).unwrap_or(1000.0),
-            max_vault_allocation_share: cfg.get("maxVaultAllocationShare").and_then(|v| v.as_f64()).unwrap_or(0.4),
-        }
-    } else {
-        OptimizerConfig { step_pct: 1, max_pool_share: 0.2, min_allocation: 1000.0, max_vault_allocation_share: 0.4 }
-    };
+    let result = optimize(optimizer_input.total_assets.0, &optimizer_input.protocols, optimizer_input.blocked_mask, &config, Some(&irm_params), asset_decimals)
+        .map_err(|e| e.context("Optimization failed"))?;
 
-    match optimize(optimizer_input.total_assets, &optimizer_input.protocols, optimizer_input.blocked_mask, &config, Some(&irm_params)) {
-        Ok(result) => {
-            log_info!("Optimization successful");
-            output_success(json!({
-                "ok": true,
-                "success": true,
-                "value": result.weights,
-                "allocations": result.allocations,
-                "allocationsDecimal": result.allocations_decimal,
-                "weights": result.weights,
-                "weightsDecimal": result.weights_decimal,
-                "expectedReturn12h": result.expected_return_12h,
-                "expectedApyWeighted": result.expected_apy_weighted,
-                "apys": result.apys,
-                "scenariosEvaluated": result.scenarios_evaluated,
-                "timeMs": result.time_ms,
-            }));
-        }
-        Err(e) => output_error(&format!("Optimization failed: {}", e)),
-    }
+    let vault = parse_vault(&params.vault)?;
+    let gas = config.gas.as_ref()
+        .map(|gas| estimate_gas_cost(&client, gas, &pinned, vault, &result.weights))
+        .transpose()
+        .map_err(|e| e.context("Failed to price rebalance gas"))?;
+
+    RebalanceOutput::new(result.with_gas(gas), Some(pinned_block), Some(vault), asset_decimals)
 }
 
 /// Legacy mode: use protocol data directly from input
-pub fn run_legacy(input: Value) {
+pub fn run_legacy(optimizer_input: OptimizerInput) -> Result<RebalanceOutput, ModuleError> {
     log_info!("Running rebalance in legacy mode (direct protocol data)");
 
-    let optimizer_input: OptimizerInput = match serde_json::from_value(input) {
-        Ok(v) => v,
-        Err(e) => {
-            output_error(&format!("Invalid input JSON: {}", e));
-            return;
-        }
-    };
+    let config = optimizer_input.config.unwrap_or_default();
+    let vault = optimizer_input.vault.as_deref().map(parse_vault).transpose()?;
+    let asset_decimals = optimizer_input.asset_decimals.unwrap_or(0);
+    if asset_decimals > MAX_DECIMALS {
+        return Err(ModuleError::InvalidInput(format!("assetDecimals must be at most {}", MAX_DECIMALS)));
+    }
 
-    let config = optimizer_input.config.unwrap_or(OptimizerConfig {
-        step_pct: 1, max_pool_share: 0.2, min_allocation: 1000.0, max_vault_allocation_share: 0.4,
-    });
+    let gas = config.gas.as_ref().map(|gas| configured_gas_cost(gas, asset_decimals)).transpose()?;
 
-    match optimize(optimizer_input.total_assets, &optimizer_input.protocols, optimizer_input.blocked_mask, &config, None) {
-        Ok(result) => {
-            log_info!("Optimization successful");
-            output_success(json!({
-                "ok": true,
-                "success": true,
-                "value": result.weights,
-                "allocations": result.allocations,
-                "allocationsDecimal": result.allocations_decimal,
-                "weights": result.weights,
-                "weightsDecimal": result.weights_decimal,
-                "expectedReturn12h": result.expected_return_12h,
-                "expectedApyWeighted": result.expected_apy_weighted,
-                "apys": result.apys,
-                "scenariosEvaluated": result.scenarios_evaluated,
-                "timeMs": result.time_ms,
-            }));
-        }
-        Err(e) => output_error(&format!("Optimization failed: {}", e)),
-    }
+    let result = optimize(optimizer_input.total_assets.0, &optimizer_input.protocols, optimizer_input.blocked_mask, &config, None, asset_decimals)
+        .map_err(|e| e.context("Optimization failed"))?;
+    log_info!("Optimization successful");
+    RebalanceOutput::new(result.with_gas(gas), None, vault, asset_decimals)
+}
+
+fn parse_vault(vault: &str) -> Result<Address, ModuleError> {
+    vault.parse()
+        .map_err(|e| ModuleError::InvalidInput(format!("Invalid vault address: {}", e)))
+}
+
+fn parse_address(address: &str) -> Result<Address, ModuleError> {
+    address.parse()
+        .map_err(|e| ModuleError::InvalidInput(format!("Invalid address {}: {}", address, e)))
 }
 
 // ============================================================================
@@ -1082,12 +2218,21 @@ pub fn run_legacy(input: Value) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    fn rpc_input(input: Value) -> RpcRebalanceInput {
+        serde_json::from_value(input).expect("valid RPC input")
+    }
+
+    fn amount(units: f64) -> TokenAmount {
+        TokenAmount(U256::from(units as u128))
+    }
 
     fn make_protocol(our_balance: f64, pool_supply: f64) -> ProtocolState {
         ProtocolState {
-            our_balance,
-            pool_supply,
-            pool_borrow: pool_supply * 0.8,
+            our_balance: amount(our_balance),
+            pool_supply: amount(pool_supply),
+            pool_borrow: amount(pool_supply * 0.8),
             utilization: 0.8,
             current_apy: 0.05,
             is_blocked: false,
@@ -1112,28 +2257,28 @@ mod tests {
         let allocs_at_limit = vec![4_000_000.0, 2_000_000.0, 2_000_000.0, 1_000_000.0, 1_000_000.0];
         assert!(is_valid_allocation(
             &allocs_at_limit, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "40% allocation should be valid");
 
         // Test 2: 41% allocation should FAIL (exceeds 40% limit)
         let allocs_over_limit = vec![4_100_000.0, 2_000_000.0, 1_900_000.0, 1_000_000.0, 1_000_000.0];
         assert!(!is_valid_allocation(
             &allocs_over_limit, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "41% allocation should be invalid");
 
         // Test 3: Multiple protocols at 40% should be valid (individual check)
         let allocs_multiple_at_limit = vec![4_000_000.0, 4_000_000.0, 1_000_000.0, 500_000.0, 500_000.0];
         assert!(is_valid_allocation(
             &allocs_multiple_at_limit, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "Two protocols at 40% should be valid");
 
         // Test 4: 50% allocation should FAIL
         let allocs_half = vec![5_000_000.0, 2_000_000.0, 1_500_000.0, 1_000_000.0, 500_000.0];
         assert!(!is_valid_allocation(
             &allocs_half, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "50% allocation should be invalid");
     }
 
@@ -1154,14 +2299,14 @@ mod tests {
         let allocs_valid_30 = vec![900_000.0, 900_000.0, 900_000.0];
         assert!(is_valid_allocation(
             &allocs_valid_30, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_30, total_assets
+            max_pool_share, min_allocation, max_vault_30, total_assets, f64::INFINITY
         ), "All allocations at 30% should be valid with 30% limit");
 
         // Test allocation exceeding 30% (1M = 33.3% > 30%)
         let allocs_over_30 = vec![1_000_000.0, 1_000_000.0, 1_000_000.0];
         assert!(!is_valid_allocation(
             &allocs_over_30, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_30, total_assets
+            max_pool_share, min_allocation, max_vault_30, total_assets, f64::INFINITY
         ), "33% allocation should be invalid with 30% limit");
 
         // Test with 100% vault limit (disabled)
@@ -1169,7 +2314,7 @@ mod tests {
         let allocs_all_in_one = vec![3_000_000.0, 0.0, 0.0];
         assert!(is_valid_allocation(
             &allocs_all_in_one, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_100, total_assets
+            max_pool_share, min_allocation, max_vault_100, total_assets, f64::INFINITY
         ), "100% in one protocol should be valid with 100% limit");
     }
 
@@ -1189,17 +2334,809 @@ mod tests {
         let allocs_too_much = vec![3_000_000.0];
         assert!(!is_valid_allocation(
             &allocs_too_much, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "Exceeding pool share should fail");
 
         // Allocating 2M should pass (2M / 12M = 16.7% of new pool)
         let allocs_ok = vec![2_000_000.0];
         assert!(is_valid_allocation(
             &allocs_ok, &protocols, blocked_mask,
-            max_pool_share, min_allocation, max_vault_allocation_share, total_assets
+            max_pool_share, min_allocation, max_vault_allocation_share, total_assets, f64::INFINITY
         ), "Within pool share should pass");
     }
 
+    /// ABI-encode a VaultDataReader snapshot the way `getSnapshot` returns it
+    fn encode_snapshot_result(total_assets: u64, protocols: &[(u8, u64, u64, u64)], timestamp: u64) -> Vec<u8> {
+        let uint = |v: u64| Token::Uint(U256::from(v));
+        let protocol_tokens = protocols.iter().enumerate()
+            .map(|(i, &(protocol_type, our_balance, supply, borrow))| Token::Tuple(vec![
+                uint(protocol_type as u64),
+                Token::Address(Address::from_low_u64_be(0x100 + i as u64)),
+                uint(our_balance),
+                uint(supply),
+                uint(borrow),
+                Token::Uint(U256::from(borrow) * U256::exp10(18) / U256::from(supply)),
+                Token::Uint(U256::exp10(16) * 4),
+                // kink1, rateAtKink1, kink2, rateAtKink2, rateAtMax, reserveFactor (bps)
+                Token::Tuple(vec![uint(9000), uint(400), uint(0), uint(0), uint(7500), uint(1000)]),
+                uint(supply),
+                uint(supply),
+                uint(supply - supply / 2000),
+                uint(timestamp - 86_400),
+            ]))
+            .collect();
+
+        ethabi::encode(&[Token::Tuple(vec![
+            Token::Address(Address::from_low_u64_be(0xa0)),
+            uint(total_assets),
+            uint(0),
+            Token::Array(vec![]),
+            uint(0),
+            uint(43_200),
+            uint(timestamp),
+            Token::Array(protocol_tokens),
+            Token::Tuple(vec![uint(0), Token::Bool(false), Token::Bool(false)]),
+        ])])
+    }
+
+    /// RPC-mode input and the recorded snapshot and decimals() exchanges it replays
+    fn replay_fixture() -> (Value, Vec<Value>) {
+        let reader = "0x00000000000000000000000000000000000000d1";
+        let vault = "0x00000000000000000000000000000000000000f1";
+        let pools: Vec<String> = (0..3).map(|i| format!("{:?}", Address::from_low_u64_be(0x100 + i))).collect();
+        let protocol_types = [1u8, 2, 4];
+
+        let call_data = vault_reader::encode_get_snapshot_call(vault, &protocol_types, &pools).unwrap();
+        let snapshot = encode_snapshot_result(
+            10_000_000_000_000,
+            &[
+                (1, 4_000_000_000_000, 1_000_000_000_000_000, 800_000_000_000_000),
+                (2, 3_000_000_000_000, 500_000_000_000_000, 420_000_000_000_000),
+                (4, 3_000_000_000_000, 300_000_000_000_000, 0),
+            ],
+            1_700_000_000,
+        );
+
+        // The snapshot and asset decimals() eth_calls at the pinned block, as captured by
+        // WASM_RPC_CASSETTE_MODE=record
+        let entry = json!({
+            "request": {
+                "jsonrpc": "2.0", "id": 1, "method": "eth_call", "chainId": 1,
+                "params": [{ "to": reader, "data": format!("0x{}", hex::encode(&call_data)) }, "0x10"]
+            },
+            "response": { "jsonrpc": "2.0", "id": 1, "result": format!("0x{}", hex::encode(&snapshot)) }
+        });
+        let decimals = json!({
+            "request": {
+                "jsonrpc": "2.0", "id": 2, "method": "eth_call", "chainId": 1,
+                "params": [{ "to": "0x00000000000000000000000000000000000000a0", "data": "0x313ce567" }, "0x10"]
+            },
+            "response": { "jsonrpc": "2.0", "id": 2, "result": format!("0x{}", hex::encode(ethabi::encode(&[Token::Uint(U256::from(6))]))) }
+        });
+        let input = json!({
+            "vaultDataReader": reader,
+            "vault": vault,
+            "protocolTypes": protocol_types,
+            "pools": pools,
+            "chainId": 1,
+            "blockNumber": 16,
+            "config": { "stepPct": 5, "maxPoolShare": 0.2, "minAllocation": 1000, "maxVaultAllocationShare": 0.5 }
+        });
+        (input, vec![entry, decimals])
+    }
+
+    fn write_cassette(name: &str, entries: &[Value]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("rebalance-{}-{}.jsonl", name, std::process::id()));
+        let lines: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_replayed_rpc_run_decodes_transforms_and_optimizes() {
+        let (input, entries) = replay_fixture();
+        let path = write_cassette("cassette", &[entries.clone(), entries].concat());
+
+        let input = rpc_input(input);
+        let config = RpcConfig::from_cassette(&path).unwrap();
+        let first = rebalance_with_rpc(&input, &config).expect("replayed run should succeed");
+        let second = rebalance_with_rpc(&input, &config).expect("replayed run should succeed");
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(first.block_number, Some(16));
+        assert_eq!(first.asset_decimals, 6);
+        assert_eq!(first.human.total_assets, "10000000");
+        assert_eq!(first.optimization.weights, second.optimization.weights);
+        assert_eq!(first.optimization.allocations, second.optimization.allocations);
+
+        let allocations = &first.optimization.allocations_decimal;
+        assert_eq!(allocations.len(), 3);
+        let total: f64 = allocations.iter().sum();
+        assert!((total - 10_000_000_000_000.0).abs() < 1.0, "allocations should cover total assets");
+        assert!(allocations.iter().all(|&a| a <= 5_000_000_000_000.0 + 1.0), "vault share cap respected");
+    }
+
+    #[test]
+    fn test_rebalance_output_has_same_fields_in_both_modes() {
+        let (input, entries) = replay_fixture();
+        let path = write_cassette("fields", &entries);
+        let rpc = rebalance_with_rpc(&rpc_input(input.clone()), &RpcConfig::from_cassette(&path).unwrap())
+            .expect("replayed run should succeed");
+        let _ = std::fs::remove_file(&path);
+
+        // The fixture's snapshot, given directly
+        let legacy = run_legacy(serde_json::from_value(json!({
+            "totalAssets": 10_000_000_000_000u64,
+            "protocols": [
+                { "ourBalance": 4_000_000_000_000u64, "poolSupply": 1_000_000_000_000_000u64, "poolBorrow": 800_000_000_000_000u64,
+                  "utilization": 0.8, "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 3_000_000_000_000u64, "poolSupply": 500_000_000_000_000u64, "poolBorrow": 420_000_000_000_000u64,
+                  "utilization": 0.84, "currentApy": 0.035, "isBlocked": false, "protocolType": 2 },
+                { "ourBalance": 3_000_000_000_000u64, "poolSupply": 300_000_000_000_000u64, "poolBorrow": 0,
+                  "utilization": 0.0, "currentApy": 0.05, "isBlocked": false, "protocolType": 4 }
+            ],
+            "config": input["config"],
+            "vault": input["vault"],
+            "assetDecimals": 6
+        })).unwrap()).unwrap();
+
+        fn key_paths(value: &Value, prefix: &str, paths: &mut std::collections::BTreeSet<String>) {
+            match value {
+                Value::Object(map) => for (key, value) in map {
+                    let path = format!("{}.{}", prefix, key);
+                    key_paths(value, &path, paths);
+                    paths.insert(path);
+                },
+                Value::Array(items) => for item in items {
+                    key_paths(item, &format!("{}[]", prefix), paths);
+                },
+                _ => {}
+            }
+        }
+        let keys = |output: &RebalanceOutput| {
+            let mut paths = std::collections::BTreeSet::new();
+            key_paths(&serde_json::to_value(output).unwrap(), "", &mut paths);
+            paths
+        };
+        assert_eq!(keys(&legacy), keys(&rpc));
+
+        let legacy = serde_json::to_value(legacy).unwrap();
+        let rpc = serde_json::to_value(rpc).unwrap();
+        assert_eq!(legacy["value"], legacy["weights"]);
+        assert_eq!(legacy["blockNumber"], Value::Null);
+        assert_eq!(rpc["blockNumber"], json!(16));
+    }
+
+    #[test]
+    fn test_wad_weights_sum_exactly_to_wad() {
+        // xorshift64: reproducible pseudo-random vectors without a rand dependency
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..2000 {
+            let n = 1 + (next() % 8) as usize;
+            let raw: Vec<f64> = (0..n).map(|_| (next() % 1_000_000) as f64).collect();
+            let total: f64 = raw.iter().sum();
+            if total == 0.0 {
+                continue;
+            }
+            let weights: Vec<f64> = raw.iter().map(|&r| r / total).collect();
+
+            let wad = weights_to_wad(&weights);
+            let sum = wad.iter().fold(U256::zero(), |acc, &w| acc + w);
+            assert_eq!(sum, U256::from(WAD), "weights {:?}", weights);
+            for (&w, &exact) in wad.iter().zip(weights.iter()) {
+                let diff = (w.low_u128() as f64 - exact * WAD as f64).abs();
+                assert!(diff <= 1e3, "weight {} too far from {}", w, exact);
+            }
+        }
+
+        // Thirds: the leftover unit goes to the lowest index among equal remainders
+        let thirds = weights_to_wad(&[1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0]);
+        assert_eq!(thirds, vec![
+            U256::from(333_333_333_333_333_334u128),
+            U256::from(333_333_333_333_333_333u128),
+            U256::from(333_333_333_333_333_333u128),
+        ]);
+        assert_eq!(weights_to_wad(&[0.0, 0.0]), vec![U256::zero(); 2]);
+        assert_eq!(format_hex(&[U256::from(WAD)])[0], format!("0x{:064x}", WAD));
+    }
+
+    #[test]
+    fn test_token_amount_parsing_is_exact_or_fails() {
+        let parse = |v: Value| TokenAmount::parse(&v);
+        assert_eq!(parse(json!(10_000_000)).unwrap(), amount(1e7));
+        assert_eq!(parse(json!(1e9)).unwrap(), amount(1e9));
+        assert_eq!(parse(json!("0x10")).unwrap(), amount(16.0));
+        let big = "340282366920938463463374607431768211457"; // 2^128 + 1
+        assert_eq!(parse(json!(big)).unwrap().0, (U256::one() << 128) + 1);
+        assert_eq!(serde_json::to_value(parse(json!(big)).unwrap()).unwrap(), json!(big));
+
+        for invalid in [json!(0.5), json!(-1), json!(1e17), json!("1e3"), json!(null), json!(format!("1{}", "0".repeat(78)))] {
+            assert!(parse(invalid.clone()).is_err(), "{} should be rejected", invalid);
+        }
+        assert!(parse(json!(1e17)).unwrap_err().contains("decimal string"));
+    }
+
+    #[test]
+    fn test_legacy_fixture_parses_but_inexact_amounts_are_rejected() {
+        use crate::action::ActionHandler;
+
+        let fixture: Value = serde_json::from_str(include_str!("../../test-input.json")).unwrap();
+        let run = |input: &Value| serde_json::from_str::<Value>(&Rebalance.execute(input)).unwrap();
+        assert_eq!(run(&fixture)["ok"], true);
+
+        // Float amounts that were rounded before the switch to exact integers now fail
+        for (field, amount) in [("ourBalance", json!(2_000_000.5)), ("poolSupply", json!(1e20))] {
+            let mut input = fixture.clone();
+            input["protocols"][0][field] = amount.clone();
+            let output = run(&input);
+            assert_eq!(output["ok"], false, "{} {}", field, amount);
+            assert_eq!(output["result"]["code"], "INVALID_INPUT");
+            assert!(output["result"]["error"].as_str().unwrap().contains("amount"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_config_amounts_are_whole_tokens_unless_raw() {
+        assert_eq!(format_units(U256::from(1_500_000u64), 6), "1.5");
+        assert_eq!(format_units(U256::from(42u64), 6), "0.000042");
+        assert_eq!(format_units(U256::from(7_000_000u64), 6), "7");
+        assert_eq!(format_units(U256::from(123u64), 0), "123");
+        assert_eq!(format_units(U256::MAX, MAX_DECIMALS).len(), 79);
+
+        let config: OptimizerConfig = serde_json::from_value(json!({ "minAllocation": 1000, "minImprovementAbs": 2 })).unwrap();
+        assert_eq!(config.min_allocation_units(6), 1e9);
+        assert_eq!(config.min_improvement_abs_units(6), 2e6);
+        let raw: OptimizerConfig = serde_json::from_value(json!({ "minAllocation": 1000, "minAllocationRaw": "5000" })).unwrap();
+        assert_eq!(raw.min_allocation_units(6), 5000.0);
+
+        // 1 whole token is 1e6 raw units: 0.4 tokens is below a 1-token minimum, 2 tokens is not
+        let input = |min_allocation: Value| serde_json::from_value::<OptimizerInput>(json!({
+            "totalAssets": "10000000",
+            "assetDecimals": 6,
+            "protocols": [
+                { "ourBalance": "9600000", "poolSupply": "1000000000", "poolBorrow": "0", "utilization": 0.0,
+                  "currentApy": 0.05, "isBlocked": false, "protocolType": 4 },
+                { "ourBalance": "400000", "poolSupply": "1000000000", "poolBorrow": "0", "utilization": 0.0,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 4 }
+            ],
+            "config": { "stepPct": 4, "maxVaultAllocationShare": 0.96, "minAllocation": min_allocation }
+        })).unwrap();
+        let human = run_legacy(input(json!(1))).unwrap();
+        assert_eq!(human.human.total_assets, "10");
+        assert!(human.optimization.allocations_decimal.iter().all(|&a| a == 0.0 || a >= 1e6));
+
+        let output = serde_json::to_value(run_legacy(input(json!(0))).unwrap()).unwrap();
+        assert_eq!(output["assetDecimals"], 6);
+        assert_eq!(output["human"]["allocations"], json!(["9.6", "0.4"]));
+
+        let invalid = serde_json::from_value::<OptimizerInput>(json!({
+            "totalAssets": "1", "assetDecimals": 78, "protocols": []
+        })).unwrap();
+        assert_eq!(run_legacy(invalid).unwrap_err().code(), "INVALID_INPUT");
+    }
+
+    #[test]
+    fn test_allocations_are_exact_integers_summing_to_total_assets() {
+        // Beyond 2^128: the old `low_u128() as f64` path would have truncated this
+        let total = (U256::one() << 130) + 12_345;
+        let pool = (U256::one() << 140).to_string();
+        let protocol = |balance: U256, apy: f64| json!({
+            "ourBalance": balance.to_string(), "poolSupply": pool, "poolBorrow": "0", "utilization": 0.0,
+            "currentApy": apy, "isBlocked": false, "protocolType": 4
+        });
+        let input: OptimizerInput = serde_json::from_value(json!({
+            "totalAssets": total.to_string(),
+            "protocols": [protocol(total / 3, 0.04), protocol(total / 3, 0.05), protocol(total - total / 3 * 2, 0.03)],
+            "config": { "stepPct": 10, "minAllocation": 0, "maxVaultAllocationShare": 0.5 }
+        })).unwrap();
+
+        let output = run_legacy(input).unwrap();
+        let allocations: Vec<U256> = output.optimization.allocations.iter()
+            .map(|a| U256::from_str_radix(&a[2..], 16).unwrap())
+            .collect();
+        assert_eq!(allocations.iter().fold(U256::zero(), |acc, &a| acc + a), total);
+        assert!(allocations.iter().all(|&a| a <= total / 2 + 1), "vault share cap respected");
+    }
+
+    #[test]
+    fn test_rebalance_output_carries_execute_rebalance_calldata() {
+        let input: OptimizerInput = serde_json::from_value(json!({
+            "totalAssets": 10_000_000.0,
+            "vault": "0x00000000000000000000000000000000000000f1",
+            "protocols": [
+                { "ourBalance": 5_000_000.0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 5_000_000.0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 }
+            ],
+            "config": { "stepPct": 10, "maxVaultAllocationShare": 1.0 }
+        })).unwrap();
+        let output = run_legacy(input).unwrap();
+        let tx = &output.transaction;
+
+        use sha3::{Digest, Keccak256};
+        let selector = &Keccak256::digest(b"executeRebalance(uint256[])")[..4];
+        assert_eq!(tx.signature, "executeRebalance(uint256[])");
+        assert_eq!(tx.selector, format!("0x{}", hex::encode(selector)));
+        assert_eq!(tx.to.as_deref(), Some("0x00000000000000000000000000000000000000f1"));
+
+        let data = hex::decode(tx.data.strip_prefix("0x").unwrap()).unwrap();
+        assert_eq!(&data[..4], selector);
+        let decoded = decode(&[ParamType::Array(Box::new(ParamType::Uint(256)))], &data[4..]).unwrap();
+        let expected: Vec<Token> = output.value.iter()
+            .map(|w| Token::Uint(U256::from_str_radix(&w[2..], 16).unwrap()))
+            .collect();
+        assert_eq!(decoded, vec![Token::Array(expected)]);
+    }
+
+    #[test]
+    fn test_rebalance_skips_when_improvement_is_below_threshold() {
+        let input: OptimizerInput = serde_json::from_value(json!({
+            "totalAssets": 10_000_000.0,
+            "protocols": [
+                { "ourBalance": 5_000_000.0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 5_000_000.0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 }
+            ],
+            "config": { "stepPct": 10, "maxVaultAllocationShare": 1.0 }
+        })).unwrap();
+        let output = run_legacy(input).unwrap();
+        let optimal = output.optimization.expected_return_12h;
+        let current = output.optimization.current_return_12h.expect("current allocation is valid");
+        assert!(optimal >= current);
+
+        // No threshold configured: any improvement rebalances
+        assert!(optimal > current);
+        assert!(matches!(output.clone().or_skip(&OptimizerConfig::default()), RebalanceResult::Rebalance(_)));
+
+        let strict = OptimizerConfig { min_improvement_abs: optimal - current + 1.0, ..OptimizerConfig::default() };
+        let skipped = json!(output.clone().or_skip(&strict));
+        assert_eq!(skipped["skipRemainingSteps"], true);
+        assert_eq!(skipped["optimalReturn12h"], json!(optimal));
+        assert_eq!(skipped["currentReturn12h"], json!(current));
+        assert!(skipped["message"].as_str().unwrap().contains("rebalancing needs more than 0"));
+        assert!(skipped.get("value").is_none());
+
+        // 1 bp of the current return is less than the improvement here
+        let loose = OptimizerConfig { min_improvement_bps: 1.0, ..OptimizerConfig::default() };
+        assert!(optimal - current > current * 1e-4);
+        assert!(matches!(output.clone().or_skip(&loose), RebalanceResult::Rebalance(_)));
+
+        // A current allocation breaking a constraint always moves
+        let mut broken = output.clone();
+        broken.optimization.current_return_12h = None;
+        assert!(matches!(broken.or_skip(&strict), RebalanceResult::Rebalance(_)));
+
+        // Already at the optimum: nothing to gain, so skip even without a threshold
+        let mut settled: OptimizerInput = serde_json::from_value(json!({
+            "totalAssets": 10_000_000.0,
+            "protocols": [
+                { "ourBalance": 0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 }
+            ],
+            "config": { "stepPct": 10, "maxVaultAllocationShare": 1.0 }
+        })).unwrap();
+        for (protocol, &alloc) in settled.protocols.iter_mut().zip(&output.optimization.allocations_decimal) {
+            protocol.our_balance = amount(alloc);
+        }
+        let at_optimum = run_legacy(settled).unwrap();
+        assert_eq!(at_optimum.optimization.current_return_12h, Some(at_optimum.optimization.net_return_12h));
+        let skipped = json!(at_optimum.or_skip(&OptimizerConfig::default()));
+        assert_eq!(skipped["skipRemainingSteps"], true);
+        assert_eq!(skipped["requiredImprovement12h"], json!(0.0));
+
+        // An off-grid current allocation the grid cannot beat is kept as well
+        let mut behind = output;
+        behind.optimization.current_return_12h = Some(optimal * 1.001);
+        assert!(matches!(behind.or_skip(&OptimizerConfig::default()), RebalanceResult::Skip(_)));
+    }
+
+    #[test]
+    fn test_movement_and_gas_costs_are_netted_out() {
+        let input = |config: Value| serde_json::from_value::<OptimizerInput>(json!({
+            "totalAssets": 10_000_000.0,
+            "protocols": [
+                { "ourBalance": 5_000_000.0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 5_000_000.0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 }
+            ],
+            "config": config
+        })).unwrap();
+
+        let free = run_legacy(input(json!({ "stepPct": 10, "maxVaultAllocationShare": 1.0 }))).unwrap();
+        let gain = free.optimization.expected_return_12h - free.optimization.current_return_12h.unwrap();
+        assert!(gain > 0.0);
+        assert_ne!(free.optimization.allocations_decimal, vec![5e6, 5e6]);
+        assert_eq!(free.optimization.net_return_12h, free.optimization.expected_return_12h);
+
+        // Moving 1M earns far less in 12h than 50 bps of it: the solvers stay put
+        for solver in ["grid", "greedy"] {
+            let costly = run_legacy(input(json!({
+                "stepPct": 10, "maxVaultAllocationShare": 1.0, "solver": solver,
+                "protocolCosts": [{ "proportionalBps": 50 }, { "fixed": 100, "proportionalBps": 50 }]
+            }))).unwrap();
+            assert_eq!(costly.optimization.allocations_decimal, vec![5e6, 5e6], "{}", solver);
+            assert_eq!(costly.optimization.movement_cost, 0.0);
+        }
+
+        // 500k gas at 100 gwei is 0.05 ETH, priced just above and well below the gain
+        let gas = |asset_per_eth: f64| json!({ "stepPct": 10, "maxVaultAllocationShare": 1.0,
+            "gas": { "assetPerEth": asset_per_eth, "gasLimit": 500_000, "gasPriceWei": "100000000000" } });
+        let skipped = json!(run_legacy(input(gas((gain + 1.0) / 0.05))).unwrap().or_skip(&OptimizerConfig::default()));
+        assert_eq!(skipped["skipRemainingSteps"], true);
+        assert!((skipped["cost"].as_f64().unwrap() - (gain + 1.0)).abs() < 1e-6);
+        assert!(skipped["message"].as_str().unwrap().contains("net of"));
+
+        let output = run_legacy(input(gas(gain / 2.0 / 0.05))).unwrap();
+        let gas_cost = output.optimization.gas.as_ref().unwrap();
+        assert_eq!(gas_cost.gas_units, 500_000);
+        assert!((output.optimization.net_return_12h - (output.optimization.expected_return_12h - gain / 2.0)).abs() < 1e-6);
+        assert!(matches!(output.or_skip(&OptimizerConfig::default()), RebalanceResult::Rebalance(_)));
+
+        let unpriced = run_legacy(input(json!({ "gas": { "assetPerEth": 1, "gasLimit": 500_000 } })));
+        assert_eq!(unpriced.unwrap_err().code(), "INVALID_INPUT");
+    }
+
+    #[test]
+    fn test_rpc_gas_is_estimated_at_the_pinned_block() {
+        let (mut input, entries) = replay_fixture();
+        let path = write_cassette("gas-weights", &entries);
+        let weights = rebalance_with_rpc(&rpc_input(input.clone()), &RpcConfig::from_cassette(&path).unwrap())
+            .unwrap().optimization.weights;
+        let _ = std::fs::remove_file(&path);
+
+        let keeper = "0x00000000000000000000000000000000000000e1";
+        let call_data = vault_tx::execute_rebalance_calldata(&weights).unwrap();
+        // decimals() and the block header share one batch, answered out of order
+        let with_id = |value: &Value, id: usize| {
+            let mut value = value.clone();
+            value["id"] = json!(id);
+            value
+        };
+        let pinned_reads = json!({
+            "request": [
+                with_id(&entries[1]["request"], 0),
+                { "jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "chainId": 1, "params": ["0x10", false] }
+            ],
+            "response": [
+                { "jsonrpc": "2.0", "id": 1, "result": {
+                    "number": "0x10", "hash": format!("0x{}", "11".repeat(32)), "parentHash": format!("0x{}", "22".repeat(32)),
+                    "timestamp": "0x6553f100", "gasLimit": "0x1c9c380", "baseFeePerGas": "0x2540be400"
+                } },
+                with_id(&entries[1]["response"], 0)
+            ]
+        });
+        let estimate = json!({
+            "request": { "jsonrpc": "2.0", "id": 5, "method": "eth_estimateGas", "chainId": 1, "params": [
+                { "from": keeper, "to": input["vault"], "data": format!("0x{}", hex::encode(&call_data)) }, "0x10"
+            ] },
+            "response": { "jsonrpc": "2.0", "id": 5, "result": "0x493e0" }
+        });
+        let path = write_cassette("gas", &[entries[0].clone(), pinned_reads.clone(), estimate.clone()]);
+
+        // 300k gas at a 10 gwei base fee plus a 1 gwei tip, at 2000 USDC per ETH
+        input["config"]["gas"] = json!({ "assetPerEth": 2000, "from": keeper, "priorityFeeWei": "1000000000" });
+        let output = rebalance_with_rpc(&rpc_input(input.clone()), &RpcConfig::from_cassette(&path).unwrap())
+            .expect("replayed run should succeed");
+        let _ = std::fs::remove_file(&path);
+
+        // Only a header without a base fee falls back to eth_gasPrice, after the batch
+        let mut no_base_fee = pinned_reads.clone();
+        no_base_fee["response"][0]["result"].as_object_mut().unwrap().remove("baseFeePerGas");
+        let gas_price = json!({
+            "request": { "jsonrpc": "2.0", "id": 4, "method": "eth_gasPrice", "chainId": 1, "params": [] },
+            "response": { "jsonrpc": "2.0", "id": 4, "result": "0x28fa6ae00" }
+        });
+        let path = write_cassette("gas-price", &[entries[0].clone(), no_base_fee, gas_price, estimate.clone()]);
+        let legacy_chain = rebalance_with_rpc(&rpc_input(input.clone()), &RpcConfig::from_cassette(&path).unwrap())
+            .expect("replayed run without a base fee should succeed");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(json!(legacy_chain.optimization.gas), json!(output.optimization.gas));
+
+        // Pinned by hash: eth_call keeps the hash, eth_estimateGas gets the header's number
+        let hash = format!("0x{}", "11".repeat(32));
+        let by_hash = |entry: &Value, id: usize| {
+            let mut entry = with_id(entry, id);
+            entry["params"][1] = json!({ "blockHash": hash });
+            entry
+        };
+        let mut snapshot = entries[0].clone();
+        snapshot["request"] = by_hash(&entries[0]["request"], 1);
+        let header = pinned_reads["response"][0]["result"].clone();
+        let pinned_reads = json!({
+            "request": [
+                by_hash(&entries[1]["request"], 0),
+                { "jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByHash", "chainId": 1, "params": [hash, false] }
+            ],
+            "response": [with_id(&entries[1]["response"], 0), { "jsonrpc": "2.0", "id": 1, "result": header }]
+        });
+        let path = write_cassette("gas-by-hash", &[snapshot.clone(), pinned_reads.clone(), estimate]);
+        input.as_object_mut().unwrap().remove("blockNumber");
+        input["blockHash"] = json!(hash);
+        input["config"]["gas"] = json!({ "assetPerEth": 2000, "from": keeper, "gasPriceWei": "11000000000" });
+        let pinned = rebalance_with_rpc(&rpc_input(input.clone()), &RpcConfig::from_cassette(&path).unwrap())
+            .expect("replayed run pinned by hash should succeed");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(json!(pinned.optimization.gas), json!(output.optimization.gas));
+        assert_eq!(pinned.block_hash.as_deref(), Some(hash.as_str()));
+
+        // A hash the node does not know never falls back to estimating at latest
+        let mut unknown = pinned_reads;
+        unknown["response"][1]["result"] = Value::Null;
+        let path = write_cassette("gas-unknown-hash", &[snapshot, unknown]);
+        let error = rebalance_with_rpc(&rpc_input(input), &RpcConfig::from_cassette(&path).unwrap()).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(error.code(), "INVALID_INPUT");
+        assert!(error.to_string().contains("Unknown block hash"), "{}", error);
+
+        let gas = output.optimization.gas.as_ref().unwrap();
+        assert_eq!(gas.gas_units, 300_000);
+        assert_eq!(gas.gas_price_wei.0, U256::from(11_000_000_000u64));
+        assert!((gas.cost - 6.6e6).abs() < 1e-3, "6.6 USDC in raw units, got {}", gas.cost);
+        assert_eq!(output.optimization.weights, weights);
+        assert!((output.human.net_return_12h - (output.human.expected_return_12h - 6.6)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_deterministic_output_is_byte_identical() {
+        let input = json!({
+            "deterministic": true,
+            "totalAssets": 10_000_000.0,
+            "protocols": [
+                { "ourBalance": 4_000_000.0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 6_000_000.0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 }
+            ],
+            "config": { "stepPct": 5, "maxVaultAllocationShare": 1.0 }
+        });
+        let render = || {
+            let started = std::time::Instant::now();
+            let outcome = Rebalance.run(serde_json::from_value(input.clone()).unwrap());
+            crate::common::render("rebalance", &input, outcome, Some(crate::common::Telemetry::since(started)), true)
+        };
+
+        let first = render();
+        assert_eq!(first, render());
+        assert!(!first.contains("timeMs"), "{}", first);
+    }
+
+    #[test]
+    fn test_rebalance_input_mode_follows_vault_data_reader() {
+        let legacy: RebalanceInput = serde_json::from_value(json!({
+            "action": "rebalance", "totalAssets": 1000.0, "protocols": []
+        })).unwrap();
+        assert!(matches!(legacy, RebalanceInput::Legacy(_)));
+
+        let rpc: RebalanceInput = serde_json::from_value(json!({
+            "vaultDataReader": "0x00000000000000000000000000000000000000d1", "vault": "0x00000000000000000000000000000000000000f1"
+        })).unwrap();
+        assert!(matches!(rpc, RebalanceInput::Rpc(_)));
+
+        // The error names the missing field of the selected mode
+        let error = serde_json::from_value::<RebalanceInput>(json!({ "vaultDataReader": "0x00" })).unwrap_err();
+        assert!(error.to_string().contains("missing field `vault`"), "{}", error);
+    }
+
+    #[test]
+    fn test_rpc_run_errors_are_classified() {
+        let path = std::env::temp_dir().join(format!("rebalance-empty-cassette-{}.jsonl", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let config = RpcConfig::from_cassette(&path).unwrap();
+
+        let input = json!({
+            "vaultDataReader": "0x00000000000000000000000000000000000000d1",
+            "vault": "not-an-address",
+            "protocolTypes": [1],
+            "pools": ["0x0000000000000000000000000000000000000100"],
+            "blockNumber": 16
+        });
+        let invalid = rebalance_with_rpc(&rpc_input(input.clone()), &config).unwrap_err();
+        assert_eq!(invalid.code(), "INVALID_INPUT");
+        assert!(!invalid.is_retryable());
+
+        // Nothing recorded for the snapshot call: the transport fails, which is retryable
+        let mut input = input;
+        input["vault"] = json!("0x00000000000000000000000000000000000000f1");
+        let missing = rebalance_with_rpc(&rpc_input(input), &config);
+        let _ = std::fs::remove_file(&path);
+        let missing = missing.unwrap_err();
+        assert_eq!(missing.code(), "RPC_TRANSPORT");
+        assert!(missing.is_retryable());
+    }
+
+    #[test]
+    fn test_greedy_solver_matches_grid() {
+        let assert_matches = |total_assets: U256, protocols: &[ProtocolState], blocked_mask: u8, config: OptimizerConfig, irm_params: Option<&[IRMParams]>| {
+            let grid = optimize(total_assets, protocols, blocked_mask, &config, irm_params, 0).unwrap();
+            let greedy_config = OptimizerConfig { solver: Solver::Greedy, ..config };
+            let greedy = optimize(total_assets, protocols, blocked_mask, &greedy_config, irm_params, 0).unwrap();
+            assert_eq!(greedy.weights, grid.weights);
+            assert!((greedy.expected_return_12h - grid.expected_return_12h).abs() <= grid.expected_return_12h.abs() * 1e-12);
+        };
+
+        // Vault-share limited, distinct pools
+        let protocols: Vec<ProtocolState> = (0..5)
+            .map(|i| make_protocol(2_000_000.0, 1_000_000_000.0 * (i + 1) as f64))
+            .collect();
+        let config = OptimizerConfig { step_pct: 5, min_allocation: 100_000.0, ..OptimizerConfig::default() };
+        assert_matches(amount(1e7).0, &protocols, 0, config.clone(), None);
+        // Pool-share limited, with a blocked protocol that can only shrink
+        assert_matches(amount(5e8).0, &protocols, 0b100, OptimizerConfig { max_vault_allocation_share: 1.0, ..config }, None);
+
+        // MetaMorpho dilution and mixed protocol types
+        let input: OptimizerInput = serde_json::from_value(json!({
+            "totalAssets": 10_000_000.0,
+            "protocols": [
+                { "ourBalance": 5_000_000.0, "poolSupply": 1e9, "poolBorrow": 8e8, "utilization": 0.8,
+                  "currentApy": 0.04, "isBlocked": false, "protocolType": 1 },
+                { "ourBalance": 3_000_000.0, "poolSupply": 5e8, "poolBorrow": 4e8, "utilization": 0.8,
+                  "currentApy": 0.035, "isBlocked": false, "protocolType": 2 },
+                { "ourBalance": 2_000_000.0, "poolSupply": 4e7, "poolBorrow": 0, "utilization": 0.0,
+                  "currentApy": 0.06, "isBlocked": false, "protocolType": 4 }
+            ]
+        })).unwrap();
+        let config = OptimizerConfig { step_pct: 2, max_vault_allocation_share: 0.6, ..OptimizerConfig::default() };
+        assert_matches(input.total_assets.0, &input.protocols, 0, config, None);
+
+        // Snapshot IRM parameters, as in the replayed RPC run
+        let snapshot = vault_reader::decode_vault_snapshot(&encode_snapshot_result(
+            10_000_000_000_000,
+            &[
+                (1, 4_000_000_000_000, 1_000_000_000_000_000, 800_000_000_000_000),
+                (2, 3_000_000_000_000, 500_000_000_000_000, 420_000_000_000_000),
+                (4, 3_000_000_000_000, 300_000_000_000_000, 0),
+            ],
+            1_700_000_000,
+        )).unwrap();
+        let (input, irm_params) = transform_snapshot_to_input(snapshot);
+        let config = OptimizerConfig { step_pct: 5, max_vault_allocation_share: 0.5, ..OptimizerConfig::default() };
+        assert_matches(input.total_assets.0, &input.protocols, input.blocked_mask, config, Some(&irm_params));
+    }
+
+    #[test]
+    fn test_branch_and_bound_finds_the_exhaustive_grid_optimum() {
+        /// Every bounded grid point in generation order, without pruning
+        fn points(depth: usize, remaining: usize, max_steps: &[usize], current: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+            if depth == max_steps.len() - 1 {
+                if remaining <= max_steps[depth] {
+                    current.push(remaining);
+                    out.push(current.clone());
+                    current.pop();
+                }
+                return;
+            }
+            for k in 0..=max_steps[depth].min(remaining) {
+                current.push(k);
+                points(depth + 1, remaining - k, max_steps, current, out);
+                current.pop();
+            }
+        }
+
+        let check = |total_assets: f64, protocols: &[ProtocolState], blocked_mask: u8, config: &OptimizerConfig| {
+            let max_steps: Vec<usize> = max_weights_pct(total_assets, protocols, blocked_mask, config).iter()
+                .map(|w| (w / config.step_pct).min(100 / config.step_pct))
+                .collect();
+            let mut all = Vec::new();
+            points(0, 100 / config.step_pct, &max_steps, &mut Vec::new(), &mut all);
+            let raw = RawConfig::new(config, protocols.len(), total_assets, 0);
+
+            let mut expected: Option<(f64, Vec<f64>)> = None;
+            for steps in &all {
+                let allocations: Vec<f64> = steps.iter().map(|&k| grid_weight(k, config.step_pct) * total_assets).collect();
+                if !is_valid_allocation(&allocations, protocols, blocked_mask, config.max_pool_share, config.min_allocation, config.max_vault_allocation_share, total_assets, raw.max_turnover) {
+                    continue;
+                }
+                let return_12h = projected_return_12h(&allocations, &projected_apys(&allocations, protocols, None));
+                if return_12h > expected.as_ref().map_or(f64::NEG_INFINITY, |e| e.0) {
+                    expected = Some((return_12h, allocations));
+                }
+            }
+
+            let search = grid_search(total_assets, protocols, blocked_mask, config, None, &raw).unwrap();
+            let best = search.best.unwrap();
+            let (expected_return, expected_allocations) = expected.unwrap();
+            assert_eq!(best.allocations, expected_allocations);
+            assert_eq!(best.return_12h, expected_return);
+            assert!(search.scenarios_evaluated < all.len(), "{} of {} points evaluated", search.scenarios_evaluated, all.len());
+            assert!(search.nodes_visited >= search.scenarios_evaluated);
+        };
+
+        // Distinct pools at 1% steps
+        let protocols: Vec<ProtocolState> = (0..4)
+            .map(|i| make_protocol(2_500_000.0, 200_000_000.0 * (i + 1) as f64))
+            .collect();
+        let config = OptimizerConfig { step_pct: 1, max_vault_allocation_share: 0.6, ..OptimizerConfig::default() };
+        check(1e7, &protocols, 0, &config);
+        // Pool-share and minAllocation limits, and a blocked protocol that can only shrink
+        let config = OptimizerConfig { step_pct: 2, min_allocation: 3e6, max_vault_allocation_share: 1.0, ..config };
+        check(4e7, &protocols, 0b0010, &config);
+        // Identical protocols: of the tied optima, the first grid point wins
+        let twins = vec![make_protocol(1e6, 1e9); 3];
+        check(3e6, &twins, 0, &OptimizerConfig { step_pct: 5, max_vault_allocation_share: 1.0, ..OptimizerConfig::default() });
+        // A turnover limit that rules out the unconstrained optimum
+        let config = OptimizerConfig { step_pct: 2, max_vault_allocation_share: 0.6, max_turnover_pct: Some(30.0), ..OptimizerConfig::default() };
+        check(1e7, &protocols, 0, &config);
+    }
+
+    #[test]
+    fn test_turnover_limit_caps_movement() {
+        let config: OptimizerConfig = serde_json::from_value(json!({ "maxTurnoverPct": 10.0, "maxTurnover": 2.5 })).unwrap();
+        assert_eq!(config.max_turnover_units(1e7, 6), 1e6);
+        assert_eq!(config.max_turnover_units(1e8, 6), 2.5e6);
+        let config: OptimizerConfig = serde_json::from_value(json!({ "maxTurnover": 2.5, "maxTurnoverRaw": "1000" })).unwrap();
+        assert_eq!(config.max_turnover_units(1e7, 6), 1000.0);
+        assert_eq!(OptimizerConfig::default().max_turnover_units(1e7, 6), f64::INFINITY);
+
+        // Everything sits in the lowest-yielding pool
+        let protocols: Vec<ProtocolState> = (0..4)
+            .map(|i| make_protocol(if i == 0 { 1e7 } else { 0.0 }, 200_000_000.0 * (i + 1) as f64))
+            .collect();
+        let total_assets = amount(1e7).0;
+        let config = OptimizerConfig { step_pct: 5, max_vault_allocation_share: 1.0, ..OptimizerConfig::default() };
+        let free = optimize(total_assets, &protocols, 0, &config, None, 0).unwrap();
+        let moved = |result: &OptimizationResult| turnover(&result.allocations_decimal, &protocols);
+        assert!(moved(&free) > 4e6);
+
+        // Moving out and back in both count, so 40% lets 20% of the assets change pools
+        let limited = OptimizerConfig { max_turnover_pct: Some(40.0), ..config.clone() };
+        let grid = optimize(total_assets, &protocols, 0, &limited, None, 0).unwrap();
+        assert!(moved(&grid) <= 4e6);
+        assert!(grid.expected_return_12h > grid.current_return_12h.unwrap());
+        assert!(grid.expected_return_12h < free.expected_return_12h);
+        // The greedy fill keeps within the limit itself and reaches the same point without the grid
+        let greedy = optimize(total_assets, &protocols, 0, &OptimizerConfig { solver: Solver::Greedy, ..limited.clone() }, None, 0).unwrap();
+        assert_eq!(greedy.scenarios_evaluated, 1, "greedy result, not the grid fallback");
+        assert_eq!(greedy.weights, grid.weights);
+        // With next to no turnover allowed, the fill can only rebuild the current allocation
+        let tight = OptimizerConfig { max_turnover_raw: Some(amount(1.0)), ..limited.clone() };
+        let kept = water_fill(1e7, &protocols, 0, &tight, None, &RawConfig::new(&tight, 4, 1e7, 0)).unwrap();
+        assert_eq!(kept.best.unwrap().allocations, vec![1e7, 0.0, 0.0, 0.0]);
+
+        // The absolute variant
+        let absolute = OptimizerConfig { max_turnover_raw: Some(amount(4e6)), ..config };
+        assert_eq!(optimize(total_assets, &protocols, 0, &absolute, None, 0).unwrap().weights, grid.weights);
+
+        let allocations = &grid.allocations_decimal;
+        assert!(is_valid_allocation(allocations, &protocols, 0, 1.0, 0.0, 1.0, 1e7, 4e6));
+        assert!(!is_valid_allocation(allocations, &protocols, 0, 1.0, 0.0, 1.0, 1e7, 3e6));
+    }
+
+    #[test]
+    fn test_grid_is_evaluated_in_place() {
+        // C(10 + 2, 2) combinations of 3 protocols at 10% steps, each summing to 1
+        let mut sums = Vec::new();
+        let visited = visit_weight_grid(3, 10, |weights| sums.push(weights.iter().sum::<f64>()));
+        assert_eq!(visited, 66);
+        assert!(sums.iter().all(|&sum| (sum - 1.0).abs() < 1e-9));
+        assert_eq!(visit_weight_grid(0, 10, |_| panic!("no protocols, no combinations")), 0);
+
+        // Eight pools at 1% steps: billions of grid points, never held in memory
+        let protocols: Vec<ProtocolState> = (0..8)
+            .map(|i| make_protocol(1_000_000.0, 100_000_000.0 * (i + 1) as f64))
+            .collect();
+        let config = OptimizerConfig { step_pct: 1, ..OptimizerConfig::default() };
+        let result = optimize(amount(8e6).0, &protocols, 0, &config, None, 0).unwrap();
+        assert!(result.scenarios_evaluated > 0);
+        assert!(result.nodes_visited > result.scenarios_evaluated);
+        assert_eq!(result.allocations_decimal.iter().sum::<f64>(), 8e6);
+
+        let empty = optimize(U256::zero(), &protocols[..3], 0, &OptimizerConfig { step_pct: 10, ..config }, None, 0).unwrap();
+        // Every combination of the full grid is visited, and with nothing to place all are valid
+        assert_eq!((empty.scenarios_evaluated, empty.nodes_visited), (66, 66));
+    }
+
     #[test]
     fn test_optimizer_respects_vault_allocation_limit() {
         // 5 protocols with large pools (pool share won't be limiting)
@@ -1219,9 +3156,10 @@ mod tests {
             max_pool_share: 0.2,
             min_allocation: 100_000.0,
             max_vault_allocation_share: 0.4,
+            ..OptimizerConfig::default()
         };
 
-        let result = optimize(total_assets, &protocols, blocked_mask, &config, None)
+        let result = optimize(amount(total_assets).0, &protocols, blocked_mask, &config, None, 0)
             .expect("Optimization should succeed");
 
         // Check no allocation exceeds 40%
@@ -17,6 +17,12 @@ if [ ! -f "yield-optimizer.wasm" ]; then
     exit 1
 fi
 
+# `wasmtime run` provides no host.rpc_call import, so the module must use the file exchange
+if wasm-objdump --version &> /dev/null && wasm-objdump -x -j Import yield-optimizer.wasm | grep -q 'host\.rpc_call'; then
+    echo "❌ yield-optimizer.wasm imports host.rpc_call; rebuild with ./build.sh (file-rpc is the default)"
+    exit 1
+fi
+
 echo "✅ Found wasmtime and yield-optimizer.wasm"
 echo ""
 
@@ -83,15 +89,13 @@ echo ""
 echo "Running WASM module..."
 echo ""
 
-# Run with wasmtime
-OUTPUT=$(cat /tmp/rebalance-test-legacy.json | wasmtime run --dir=. yield-optimizer.wasm 2>&1)
-
-# Separate stderr (logs) and stdout (JSON result)
-LOGS=$(echo "$OUTPUT" | grep '^\[WASM' || true)
-RESULT=$(echo "$OUTPUT" | grep -v '^\[WASM' | tail -1)
+# Run with wasmtime: stdout is the JSON result, stderr is NDJSON log records
+RESULT=$(cat /tmp/rebalance-test-legacy.json | \
+  wasmtime run --dir=. --env WASM_LOG="${WASM_LOG:-info}" yield-optimizer.wasm 2>/tmp/rebalance-test-legacy.log | tail -1)
 
 echo "--- WASM Logs ---"
-echo "$LOGS"
+jq -r '"[\(.seq)] \(.level | ascii_upcase) \(.target): \(.msg)\(if .fields then " \(.fields | tojson)" else "" end)"' \
+  /tmp/rebalance-test-legacy.log || cat /tmp/rebalance-test-legacy.log
 echo ""
 
 echo "--- WASM Output ---"
@@ -106,7 +110,8 @@ if echo "$RESULT" | jq -e '.result.success' > /dev/null 2>&1; then
     echo "  Expected APY: $(echo "$RESULT" | jq -r '.result.expectedApyWeighted * 100')%"
     echo "  Expected 12h Return: \$$(echo "$RESULT" | jq -r '.result.expectedReturn12h')"
     echo "  Scenarios Evaluated: $(echo "$RESULT" | jq -r '.result.scenariosEvaluated')"
-    echo "  Time: $(echo "$RESULT" | jq -r '.result.timeMs')ms"
+    echo "  Nodes Visited: $(echo "$RESULT" | jq -r '.result.nodesVisited')"
+    echo "  Time: $(echo "$RESULT" | jq -r '.telemetry.timeMs')ms"
     echo ""
     echo "Allocations:"
     echo "$RESULT" | jq -r '.result.allocationsDecimal | to_entries[] | "  Protocol \(.key): $\(.value | floor)"'
@@ -161,6 +166,6 @@ echo "  echo '{...rpc-input...}' | wasmtime run --dir=/tmp yield-optimizer.wasm"
 echo ""
 
 # Cleanup
-rm -f /tmp/rebalance-test-legacy.json
+rm -f /tmp/rebalance-test-legacy.json /tmp/rebalance-test-legacy.log
 
 echo "=== Test Complete ==="
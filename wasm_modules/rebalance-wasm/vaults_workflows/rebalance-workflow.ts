@@ -98,6 +98,12 @@ async function main() {
               minAllocation: 1000, // Min $1000 allocation
               maxVaultAllocationShare: 0.4, // Max 40% of vault to any single protocol
             },
+            rpc: {
+              timeoutMs: 5000,   // Per-call wait
+              maxRetries: 2,     // Retry timeouts / transient RPC errors
+              backoffMs: 250,    // Doubled per retry
+              deadlineMs: 10000, // Leave the rest of wasmTimeoutMs for optimization
+            },
           },
           wasmTimeoutMs: 30000, // 30 seconds timeout
         } as any)